@@ -0,0 +1,275 @@
+use crate::api_types as api;
+use crate::utils;
+
+use serde::Serialize;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// connect here (with an auth token for role ADMIN) to manage the server
+pub const ADMIN_PATH: &str = "/admin";
+
+/// close code for players who were kicked (or banned) by an admin
+pub const KICKED_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Policy;
+
+/// Everything admins can see and manage outside of the game state itself:
+/// who's connected from where, who's muted, and which addresses are banned.
+pub struct AdminState {
+    connections: Mutex<HashMap<api::PlayerId, Connection>>,
+    /// (see `reserve_player_slot`)
+    player_slots_taken: AtomicUsize,
+    banned_ips: Mutex<HashSet<String>>,
+    audit: AuditTrail,
+}
+
+struct Connection {
+    ip: String,
+    out: ws::Sender,
+    identity: Option<api::Identity>,
+    quality: Option<api::ConnectionQuality>,
+    muted: bool,
+}
+
+impl std::fmt::Debug for AdminState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminState")
+            .field("banned_ips", &self.banned_ips)
+            .finish()
+    }
+}
+
+impl AdminState {
+    pub fn new(audit: AuditTrail) -> AdminState {
+        AdminState {
+            connections: Mutex::new(HashMap::new()),
+            player_slots_taken: AtomicUsize::new(0),
+            banned_ips: Mutex::new(HashSet::new()),
+            audit,
+        }
+    }
+
+    pub fn audit(&self) -> &AuditTrail {
+        &self.audit
+    }
+
+    pub fn connected(
+        &self,
+        id: api::PlayerId,
+        ip: String,
+        out: ws::Sender,
+        identity: Option<api::Identity>,
+    ) {
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                ip,
+                out,
+                identity,
+                quality: None,
+                muted: false,
+            },
+        );
+    }
+
+    pub fn disconnected(&self, id: api::PlayerId) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    pub fn measured(&self, id: api::PlayerId, quality: api::ConnectionQuality) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.quality = Some(quality);
+        }
+    }
+
+    pub fn is_connected(&self, id: api::PlayerId) -> bool {
+        self.connections.lock().unwrap().contains_key(&id)
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    /// Takes one of `max` player slots, unless they're all taken (by players, or
+    /// connections yet to become players); give it back with `release_player_slot`.
+    pub fn reserve_player_slot(&self, max: usize) -> bool {
+        self.player_slots_taken
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |taken| {
+                if taken < max {
+                    Some(taken + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn release_player_slot(&self) {
+        self.player_slots_taken.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn is_banned(&self, ip: &str) -> bool {
+        self.banned_ips.lock().unwrap().contains(ip)
+    }
+
+    pub fn is_muted(&self, id: api::PlayerId) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(connection) => connection.muted,
+            None => false,
+        }
+    }
+
+    pub fn players(&self) -> Vec<api::PlayerSummary> {
+        let connections = self.connections.lock().unwrap();
+        let mut players: Vec<api::PlayerSummary> = connections
+            .iter()
+            .map(|(id, connection)| api::PlayerSummary {
+                player_id: *id,
+                ip: connection.ip.clone(),
+                rtt_mean_ms: connection.quality.as_ref().map(|q| q.rtt_mean_ms),
+                identity: connection.identity.clone(),
+                muted: connection.muted,
+            })
+            .collect();
+        players.sort_by_key(|player| player.player_id);
+        players
+    }
+
+    pub fn kick(&self, id: api::PlayerId, reason: &str) -> Result<(), String> {
+        let connections = self.connections.lock().unwrap();
+        let connection = connections
+            .get(&id)
+            .ok_or_else(|| format!("player [{}] is not connected", id))?;
+        connection
+            .out
+            .close_with_reason(KICKED_CLOSE_CODE, reason.to_owned())
+            .map_err(|error| error.to_string())
+    }
+
+    /// kicks the player, and refuses their address from then on (returning it)
+    pub fn ban(&self, id: api::PlayerId, reason: &str) -> Result<String, String> {
+        let ip = match self.connections.lock().unwrap().get(&id) {
+            Some(connection) => connection.ip.clone(),
+            None => return Err(format!("player [{}] is not connected", id)),
+        };
+        self.banned_ips.lock().unwrap().insert(ip.clone());
+        self.kick(id, reason)?;
+        Ok(ip)
+    }
+
+    pub fn set_muted(&self, id: api::PlayerId, muted: bool) -> Result<(), String> {
+        match self.connections.lock().unwrap().get_mut(&id) {
+            Some(connection) => {
+                connection.muted = muted;
+                Ok(())
+            }
+            None => Err(format!("player [{}] is not connected", id)),
+        }
+    }
+}
+
+/// One json line per admin command: who ran what, and how it went.
+/// Always logged, and also appended to a file if given one.
+pub struct AuditTrail {
+    file: Mutex<Option<File>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuditEntry<'a> {
+    time_ms: u64,
+    admin: &'a api::Identity,
+    command: &'a api::AdminCommand,
+    ok: bool,
+    outcome: &'a str,
+}
+
+impl AuditTrail {
+    pub fn open(path: Option<&str>) -> std::io::Result<AuditTrail> {
+        let file = match path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(AuditTrail {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(
+        &self,
+        admin: &api::Identity,
+        command: &api::AdminCommand,
+        outcome: &Result<String, String>,
+    ) {
+        let (ok, outcome) = match outcome {
+            Ok(message) => (true, message),
+            Err(message) => (false, message),
+        };
+        let entry = serde_json::ser::to_string(&AuditEntry {
+            time_ms: utils::unix_time().as_millis() as u64,
+            admin,
+            command,
+            ok,
+            outcome,
+        })
+        .unwrap();
+        info!("[audit] {}", entry);
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            if let Err(error) = writeln!(file, "{}", entry) {
+                error!("failed to write audit trail: [{}]", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdminState, AuditTrail};
+    use crate::api_types as api;
+
+    use std::fs;
+
+    #[test]
+    fn audit_trail_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("tide-audit-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let audit = AuditTrail::open(path.to_str()).unwrap();
+        let admin = api::Identity {
+            player_id: 1,
+            display_name: "root".to_owned(),
+            role: api::Role::Admin,
+        };
+        audit.record(
+            &admin,
+            &api::AdminCommand::PauseMatch,
+            &Ok("paused".to_owned()),
+        );
+        audit.record(
+            &admin,
+            &api::AdminCommand::Mute { player_id: 9 },
+            &Err("player [9] is not connected".to_owned()),
+        );
+
+        let trail = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = trail
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["command"]["command"], "PAUSE_MATCH");
+        assert_eq!(lines[1]["ok"], false);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn player_slots_run_out_until_given_back() {
+        let admin = AdminState::new(AuditTrail::open(None).unwrap());
+        assert!(admin.reserve_player_slot(2));
+        assert!(admin.reserve_player_slot(2));
+        assert!(!admin.reserve_player_slot(2));
+        admin.release_player_slot();
+        assert!(admin.reserve_player_slot(2));
+    }
+}
@@ -1,7 +1,7 @@
 use serde;
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 /* This is the single data structure used for websockets messages (in json)
 
@@ -19,7 +19,11 @@ usage:
 messages you'll receive:
 
 - your assigned id: (sent immediately upon connection)
-    {"type": "YOUR_PLAYER_ID", "player_id": "1"}
+    {"type": "YOUR_PLAYER_ID", "playerId": 1, "resumeToken": "1.8243...c3f1"}
+
+  if the connection drops, reconnect with `?resumeToken=<token>` in the url
+  within the grace period to pick up where you left off (same id, position,
+  health, stats). Meanwhile, other clients see you as "DISCONNECTED".
 
 - game state: (sent periodically)
     {"type": "GAME_STATE", ... }
@@ -27,28 +31,86 @@ messages you'll receive:
 - player disconnected: (immediately upon any player's disconnection)
     {"type": "PLAYER_DISCONNECTED", "player_id": "2"}
 
+- your connection quality: (after each ping/pong, over the last few of them)
+    {"type": "CONNECTION_QUALITY", "playerId": 1, "quality": {"rttMeanMs": 41.5, ...}}
+
+- error: (e.g. upon sending messages too quickly)
+    {"type": "ERROR", "code": "RATE_LIMITED", "message": "slow down"}
+
+- server shutdown: (right before the server closes all connections)
+    {"type": "SERVER_SHUTDOWN", "reason": "server received termination signal"}
+
+- announcement: (from the admins, to everyone)
+    {"type": "ANNOUNCEMENT", "message": "restarting in 5 minutes"}
+
+- gameplay catalog: (upon connecting, before your id, and whenever it's reloaded)
+    {"type": "CATALOG", "projectiles": {"HIT_SCAN0": {"speed": null, "damage": 1, ...}, ...},
+        "enemies": {"GRUNT": {"health": 100, "hitbox": {"shape": "CUBOID", ...}, ...}, ...},
+        "spawning": {...}, "waves": [...], "pvp": {"enabled": false, ...}}
+
+  projectile types are the ids in "projectiles" (what PROJECTILE_CREATED's
+  "projectileType" refers to), and enemy types the ids in "enemies" (what each
+  enemy's "enemyType" in the game state refers to), so render weapons and enemies
+  from here rather than hardcoding them
+
+  with "pvp" enabled, projectiles hit players too (never their own shooter): with
+  "teams", each player's "team" in the game state, and teammates only with
+  "friendly_fire"
+
+spectators: (connect to `/spectate`, or with an auth token for role SPECTATOR)
+
+    you get game state and events like everyone else, but aren't in the game;
+    instead of an id you get
+    {"type": "SPECTATING", "spectatorId": 7, "following": null}
+
+    {"type": "FOLLOW", "playerId": 2}    (or "playerId": null to stop)
+  answered with SPECTATING again. Game state isn't filtered per client (yet), so
+  this only tells your client whose viewpoint to render. Gameplay messages are
+  answered with {"type": "ERROR", "code": "SPECTATORS_CANNOT_PLAY", ...}
+
+admin endpoint: (connect to `/admin` with an auth token for role ADMIN)
+
+    {"command": "LIST_PLAYERS"}
+    {"command": "KICK", "playerId": 2, "reason": "afk"}
+    {"command": "BAN", "playerId": 2, "reason": "cheating"}    (by ip address)
+    {"command": "MUTE", "playerId": 2}    (their updates are ignored, until UNMUTE)
+    {"command": "PAUSE_MATCH"}    (and RESUME_MATCH)
+    {"command": "SPAWN_ENEMIES", "count": 5, "enemyType": "GRUNT"}    (the type is optional)
+    {"command": "SET_TUNABLE", "name": "RATE_LIMIT_PROJECTILES_PER_SEC", "value": 10}
+    {"command": "ANNOUNCE", "message": "restarting in 5 minutes"}
+
+  every command is answered with
+    {"type": "ADMIN_RESULT", "ok": true, "message": "..."}
+  (and LIST_PLAYERS with {"type": "PLAYER_LIST", "players": [...]} instead)
+
 */
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ClientUpdate {
     PositionUpdate(PositionStamped),
     ProjectileCreated(ProjectileSnaphot),
     /// manual (server side) messages; client should not have access to these
+    /// (identity is None when the server doesn't require authentication)
     #[serde(skip)]
-    PlayerConnected(()),
+    PlayerConnected(Option<Identity>),
     #[serde(skip)]
     PlayerDisconnected(()),
+    #[serde(skip)]
+    ConnectionQualityMeasured(ConnectionQuality),
+    /// game commands from the admin endpoint
+    #[serde(skip)]
+    Admin(AdminCommand),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PositionStamped {
     pub xy: Vec2,
     pub time_ms: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ProjectileSnaphot {
     pub projectile_type: ProjectileType,
@@ -60,42 +122,156 @@ pub type Vec2 = nalgebra::Vector2<f32>;
 
 pub type Health = isize;
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
-pub enum ProjectileType {
-    HitScan0 = 0,
-    Projectile0 = 1,
-    // ...
-}
+/// a projectile's id in the `Catalog` (e.g. "HIT_SCAN0"); new weapons are just new entries
+pub type ProjectileType = String;
 
 pub mod projectile_info {
     use serde;
-    use serde::Serialize;
+    use serde::{Deserialize, Serialize};
 
-    #[derive(Copy, Clone, Debug, Serialize)]
+    /// (see `Catalog::projectiles`)
+    #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+    #[serde(deny_unknown_fields)]
     pub struct ProjectileInfo {
         pub speed: Option<f32>, // if None, this is hitscan (infinite speed)
+        /// (per pellet)
         pub damage: super::Health,
         /// number of enemies projectile will pass through, None indicates infinite
-        /// most projectiles will likely be 1
+        /// (each is still hit only once); most projectiles will likely be 1
         pub num_penetrations: Option<isize>,
+        /// how far it reaches, None indicates infinite
+        #[serde(default)]
+        pub range: Option<f32>,
+        /// shots per second (per player), None indicates unlimited
+        #[serde(default)]
+        pub fire_rate: Option<f32>,
+        /// the pellets fan out evenly across this angle (in degrees)
+        #[serde(default)]
+        pub spread_degrees: f32,
+        /// projectiles (or rays) per shot, e.g. for shotguns
+        #[serde(default = "one_pellet")]
+        pub pellets: u32,
     }
-    static PROJECTILE_INFOS: &'static [ProjectileInfo] = &[
-        ProjectileInfo {
-            speed: None,
-            damage: 1,
-            num_penetrations: Some(1),
-        },
-        ProjectileInfo {
-            speed: Some(2.0),
-            damage: 10,
-            num_penetrations: Some(1),
-        },
-    ];
-    pub fn lookup_projectile_info(
-        projectile_type: super::ProjectileType,
-    ) -> &'static ProjectileInfo {
-        &PROJECTILE_INFOS[projectile_type as usize]
+
+    fn one_pellet() -> u32 {
+        1
+    }
+}
+
+//----------------------------//
+//      Gameplay catalog:     //
+//----------------------------//
+
+/// What weapons and enemies are like, and how enemies spawn: loaded from a
+/// data file (see `gameplay`), and sent to clients upon connecting and whenever
+/// it changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Catalog {
+    pub projectiles: BTreeMap<ProjectileType, projectile_info::ProjectileInfo>,
+    pub enemies: BTreeMap<EnemyType, EnemyInfo>,
+    #[serde(default)]
+    pub spawning: Spawning,
+    #[serde(default)]
+    pub waves: Vec<Wave>,
+    #[serde(default)]
+    pub pvp: Pvp,
+}
+
+/// an enemy's id in the `Catalog` (e.g. "GRUNT")
+pub type EnemyType = String;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EnemyInfo {
+    pub health: Health,
+    /// units per second
+    #[serde(default)]
+    pub speed: f32,
+    pub hitbox: Hitbox,
+    /// dealt to a player within `attack_range`, once a second
+    #[serde(default)]
+    pub damage: Health,
+    #[serde(default)]
+    pub attack_range: f32,
+    /// credited to whoever kills it
+    #[serde(default)]
+    pub score_value: u64,
+    #[serde(default)]
+    pub behavior: Behavior,
+}
+
+/// what projectiles hit, centered on the enemy's position, e.g.
+/// `{"shape": "CAPSULE", "half_height": 0.5, "radius": 0.25}` (upright)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(
+    tag = "shape",
+    rename_all = "SCREAMING_SNAKE_CASE",
+    deny_unknown_fields
+)]
+pub enum Hitbox {
+    Cuboid { half_width: f32, half_height: f32 },
+    Ball { radius: f32 },
+    Capsule { half_height: f32, radius: f32 },
+}
+
+/// how an enemy goes about its business (its AI profile)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Behavior {
+    /// stays where it spawned, attacking whoever comes within range
+    #[default]
+    Stationary,
+    /// heads for the nearest connected player, until within attack range
+    Chase,
+}
+
+/// a steady trickle of enemies
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Spawning {
+    /// (0 for never)
+    pub interval_ms: u64,
+    pub count: u32,
+    /// no more are spawned while this many are alive
+    pub max_alive: u32,
+    /// (the catalog's first, when unspecified)
+    pub enemy_type: Option<EnemyType>,
+}
+
+/// enemies spawned once, this long after the match started (the first tick)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Wave {
+    pub after_ms: u64,
+    pub count: u32,
+    /// (the catalog's first, when unspecified)
+    #[serde(default)]
+    pub enemy_type: Option<EnemyType>,
+}
+
+/// Players shooting players (off by default), by the same penetration and damage
+/// rules as enemies.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pvp {
+    pub enabled: bool,
+    /// players are split evenly into this many teams (0 for every player for themselves)
+    pub teams: u32,
+    /// whether teammates can hit each other
+    pub friendly_fire: bool,
+    /// what projectiles hit, centered on the player's position
+    pub player_hitbox: Hitbox,
+}
+
+impl Default for Pvp {
+    fn default() -> Pvp {
+        Pvp {
+            enabled: false,
+            teams: 0,
+            friendly_fire: false,
+            player_hitbox: Hitbox::Ball { radius: 0.5 },
+        }
     }
 }
 
@@ -103,26 +279,189 @@ pub mod projectile_info {
 /// Messages sent to clients: ///
 ///---------------------------///
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ServerUpdate {
-    YourPlayerId(PlayerIdMessage),
+    YourPlayerId(YourPlayerIdMessage),
     PlayerDisconnected(PlayerIdMessage),
+    ServerShutdown(ServerShutdownMessage),
+    Error(ErrorMessage),
+    ConnectionQuality(ConnectionQualityMessage),
+    Announcement(AnnouncementMessage),
+    AdminResult(AdminResultMessage),
+    PlayerList(PlayerListMessage),
+    Spectating(SpectatingMessage),
+    /// upon connecting, and whenever the gameplay catalog is reloaded
+    Catalog(Catalog),
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayerIdMessage {
     pub player_id: PlayerId,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct YourPlayerIdMessage {
+    pub player_id: PlayerId,
+    /// reconnect with this to resume the session (single use)
+    pub resume_token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerShutdownMessage {
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionQualityMessage {
+    pub player_id: PlayerId,
+    pub quality: ConnectionQuality,
+}
+
+/// rolling round trip time statistics (see `latency::LatencyTracker`)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionQuality {
+    pub rtt_mean_ms: f32,
+    pub rtt_stddev_ms: f32,
+    pub rtt_min_ms: f32,
+    pub rtt_max_ms: f32,
+    /// mean difference between consecutive round trip times
+    pub jitter_ms: f32,
+    pub samples: usize,
+    /// pings which went unanswered
+    pub pongs_lost: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    RateLimited,
+    SpectatorsCannotPlay,
+    /// e.g. following a player who isn't connected
+    UnknownPlayer,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AnnouncementMessage {
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminResultMessage {
+    pub ok: bool,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerListMessage {
+    pub players: Vec<PlayerSummary>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SpectatingMessage {
+    pub spectator_id: u32,
+    pub following: Option<PlayerId>,
+}
+
+/// a connected player, as seen by admins
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerSummary {
+    pub player_id: PlayerId,
+    pub ip: String,
+    /// None until measured
+    pub rtt_mean_ms: Option<f32>,
+    pub identity: Option<Identity>,
+    pub muted: bool,
+}
+
+///----------------------------------///
+/// Commands on the admin endpoint:  ///
+///----------------------------------///
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command")]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AdminCommand {
+    ListPlayers,
+    #[serde(rename_all = "camelCase")]
+    Kick {
+        player_id: PlayerId,
+        reason: Option<String>,
+    },
+    /// kicks the player, and refuses their ip address from then on
+    #[serde(rename_all = "camelCase")]
+    Ban {
+        player_id: PlayerId,
+        reason: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Mute {
+        player_id: PlayerId,
+    },
+    #[serde(rename_all = "camelCase")]
+    Unmute {
+        player_id: PlayerId,
+    },
+    PauseMatch,
+    ResumeMatch,
+    #[serde(rename_all = "camelCase")]
+    SpawnEnemies {
+        count: u32,
+        /// (the catalog's first, when unspecified)
+        #[serde(default)]
+        enemy_type: Option<EnemyType>,
+    },
+    SetTunable {
+        name: String,
+        value: u64,
+    },
+    Announce {
+        message: String,
+    },
+}
+
+///----------------------------------///
+///     Messages from spectators:    ///
+///----------------------------------///
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SpectatorCommand {
+    /// whose viewpoint to watch from (None for the whole map)
+    #[serde(rename_all = "camelCase")]
+    Follow { player_id: Option<PlayerId> },
+}
+
+/// what every serialized `GameState` starts with, telling snapshots apart from other messages
+pub const GAME_STATE_JSON_PREFIX: &str = "{\"players\":";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GameState {
     pub players: HashMap<PlayerId, Player>,
     pub enemies: Vec<Enemy>,
     pub projectiles: Vec<PlayerProjectile>,
+    /// (by an admin) nothing moves, and player updates are ignored
+    pub paused: bool,
 }
 
 pub type EntityId = u32;
@@ -130,7 +469,8 @@ pub type PlayerId = EntityId;
 pub type EnemyId = EntityId;
 pub type ProjectileId = EntityId;
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum AuthorizationStatus {
     #[allow(dead_code)]
     Unspecified = 0,
@@ -139,25 +479,82 @@ pub enum AuthorizationStatus {
     FoulPlayDetected = 2, // TODO: control for malicious clients
 }
 
-#[derive(Debug, Serialize)]
+/// (what clients see, since it isn't sent to them)
+impl Default for AuthorizationStatus {
+    fn default() -> AuthorizationStatus {
+        AuthorizationStatus::Unspecified
+    }
+}
+
+/// who a client is, as vouched for by their auth token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Identity {
+    pub player_id: PlayerId,
+    pub display_name: String,
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Role {
+    Player,
+    Spectator,
+    Admin,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Player {
     pub position: PositionStamped,
     pub connection_status: ConnectionStatus,
+    pub health: Health,
+    pub stats: PlayerStats,
+    /// (only in team matches, see `Pvp::teams`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<Identity>,
+    /// None until measured (shown on the scoreboard next to stats)
+    pub connection_quality: Option<ConnectionQuality>,
 
     #[serde(skip)]
     pub authr_status: AuthorizationStatus,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStats {
+    pub shots_fired: u64,
+    pub enemies_hit: u64,
+    pub enemies_killed: u64,
+    pub damage_dealt: Health,
+    /// the `score_value`s of the enemies killed
+    pub score: u64,
+    /// (see `Pvp`)
+    #[serde(default)]
+    pub players_hit: u64,
+    #[serde(default)]
+    pub players_killed: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayerProjectile {
     pub player_id: PlayerId,
     pub projectile: ProjectileSnaphot,
     pub current_info: projectile_info::ProjectileInfo,
+    /// distance covered since being fired (it's gone once this reaches its range)
+    #[serde(default)]
+    pub travelled: f32,
+    /// (never hit twice; only the game controller keeps track)
+    #[serde(skip)]
+    pub enemies_hit: HashSet<isize>,
+    #[serde(skip)]
+    pub players_hit: HashSet<PlayerId>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ConnectionStatus {
     #[allow(dead_code)]
@@ -166,15 +563,16 @@ pub enum ConnectionStatus {
     Disconnected,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Enemy {
     pub enemy_id: isize,
+    pub enemy_type: EnemyType,
     pub position: PositionStamped,
     pub health: Health,
     pub status: EnemyStatus,
 }
-#[derive(Serialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum EnemyStatus {
     #[allow(dead_code)]
@@ -183,3 +581,39 @@ pub enum EnemyStatus {
     Alive = 1, // TODO: spawn enemies
     Dead = 2,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{GameState, SpectatorCommand, GAME_STATE_JSON_PREFIX};
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn game_state_json_starts_with_prefix() {
+        let state = GameState {
+            players: HashMap::new(),
+            enemies: vec![],
+            projectiles: vec![],
+            paused: false,
+        };
+        let json = serde_json::ser::to_string(&state).unwrap();
+        assert!(json.starts_with(GAME_STATE_JSON_PREFIX));
+    }
+
+    #[test]
+    fn spectators_follow_a_player_or_nobody() {
+        let follow: SpectatorCommand =
+            serde_json::from_str(r#"{"type":"FOLLOW","playerId":2}"#).unwrap();
+        assert!(matches!(
+            follow,
+            SpectatorCommand::Follow { player_id: Some(2) }
+        ));
+        let unfollow: SpectatorCommand =
+            serde_json::from_str(r#"{"type":"FOLLOW","playerId":null}"#).unwrap();
+        assert!(matches!(
+            unfollow,
+            SpectatorCommand::Follow { player_id: None }
+        ));
+        assert!(serde_json::from_str::<SpectatorCommand>(r#"{"type":"POSITION_UPDATE"}"#).is_err());
+    }
+}
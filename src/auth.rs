@@ -0,0 +1,141 @@
+use crate::api_types as api;
+use crate::utils;
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+/// Bearer tokens presented at handshake, either as a header:
+///     Authorization: Bearer <token>
+/// or in the url:
+///     ws://host:port/?token=<token>
+///
+/// token format: "<payload>.<signature>", where payload is the url-safe
+/// base64 (unpadded) of the json `Claims`, and signature is the hex
+/// HMAC-SHA256 of payload under the shared secret (`config::AUTH_SECRET`).
+pub const TOKEN_QUERY_PARAM: &str = "token";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Claims {
+    pub player_id: api::PlayerId,
+    pub display_name: String,
+    pub role: api::Role,
+    /// expiry, in seconds since the unix epoch
+    pub exp: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    MissingToken,
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "missing auth token"),
+            AuthError::Malformed => write!(f, "malformed auth token"),
+            AuthError::BadSignature => write!(f, "auth token signature mismatch"),
+            AuthError::Expired => write!(f, "auth token expired"),
+        }
+    }
+}
+
+/// (for tooling that hands out tokens, e.g. tests and bots)
+#[allow(dead_code)]
+pub fn issue_token(secret: &[u8], claims: &Claims) -> String {
+    let payload = base64::encode_config(
+        serde_json::ser::to_vec(claims).unwrap(),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let signature = utils::sign(secret, payload.as_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+/// `now_secs` is seconds since the unix epoch
+pub fn verify_token(secret: &[u8], token: &str, now_secs: u64) -> Result<api::Identity, AuthError> {
+    let (payload, signature) = token.rsplit_once('.').ok_or(AuthError::Malformed)?;
+    if !utils::verify_signature(secret, payload.as_bytes(), signature) {
+        return Err(AuthError::BadSignature);
+    }
+    let claims: Claims = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(AuthError::Malformed)?;
+    if claims.exp <= now_secs {
+        return Err(AuthError::Expired);
+    }
+    Ok(api::Identity {
+        player_id: claims.player_id,
+        display_name: claims.display_name,
+        role: claims.role,
+    })
+}
+
+/// the token from the `Authorization` header, falling back on the query string
+pub fn token_from_request(req: &ws::Request) -> Option<String> {
+    let from_header = req
+        .header("Authorization")
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned());
+    from_header.or_else(|| utils::query_param(req.resource(), TOKEN_QUERY_PARAM).map(str::to_owned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{issue_token, verify_token, AuthError, Claims};
+    use crate::api_types as api;
+
+    const SECRET: &[u8] = b"correct horse battery staple";
+
+    fn claims(exp: u64) -> Claims {
+        Claims {
+            player_id: 42,
+            display_name: "ferris".to_owned(),
+            role: api::Role::Player,
+            exp,
+        }
+    }
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let token = issue_token(SECRET, &claims(1_000));
+        let identity = verify_token(SECRET, &token, 999).unwrap();
+        assert_eq!(identity.player_id, 42);
+        assert_eq!(identity.display_name, "ferris");
+        assert_eq!(identity.role, api::Role::Player);
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let token = issue_token(SECRET, &claims(1_000));
+        assert_eq!(verify_token(SECRET, &token, 1_000), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let token = issue_token(SECRET, &claims(1_000));
+        let other_secret = verify_token(b"not the secret", &token, 0);
+        assert_eq!(other_secret, Err(AuthError::BadSignature));
+
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let forged_payload = base64::encode_config(
+            serde_json::ser::to_vec(&claims(u64::MAX)).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let forged = format!("{}.{}", forged_payload, signature);
+        assert_eq!(
+            verify_token(SECRET, &forged, 0),
+            Err(AuthError::BadSignature)
+        );
+
+        assert_eq!(
+            verify_token(SECRET, "garbage", 0),
+            Err(AuthError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use crate::config;
+
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+
+/// Estimates how far behind a client is in reading what we send it.
+///
+/// ws doesn't expose a connection's outbound buffer, so pings serve as markers
+/// instead: a ping is queued behind everything sent before it, and the client
+/// can only answer it once it has read all of that. So the oldest unanswered
+/// ping tells how long the client has been sitting on unread data.
+#[derive(Debug)]
+pub struct OutboundMonitor {
+    /// payloads (send times, in ns) of pings not yet answered, oldest first
+    unanswered_pings: VecDeque<u64>,
+    coalesce_after: Duration,
+    disconnect_after: Duration,
+}
+
+/// what to do with an outgoing game state snapshot
+#[derive(Debug, PartialEq, Eq)]
+pub enum SnapshotVerdict {
+    Send,
+    /// hold on to it (replacing any held before) until the client catches up
+    Coalesce,
+    /// the client isn't keeping up, close the connection
+    Disconnect,
+}
+
+impl OutboundMonitor {
+    pub fn new(coalesce_after: Duration, disconnect_after: Duration) -> OutboundMonitor {
+        OutboundMonitor {
+            unanswered_pings: VecDeque::new(),
+            coalesce_after,
+            disconnect_after,
+        }
+    }
+
+    pub fn from_config() -> OutboundMonitor {
+        OutboundMonitor::new(
+            Duration::from_millis(config::SLOW_CLIENT_COALESCE_AFTER_MS()),
+            Duration::from_millis(config::SLOW_CLIENT_DISCONNECT_AFTER_MS()),
+        )
+    }
+
+    pub fn ping_sent(&mut self, sent_ns: u64) {
+        self.unanswered_pings.push_back(sent_ns);
+    }
+
+    /// (answering a ping implies having read the ones before it too)
+    pub fn pong_received(&mut self, sent_ns: u64) {
+        while let Some(oldest) = self.unanswered_pings.front() {
+            if *oldest > sent_ns {
+                break;
+            }
+            self.unanswered_pings.pop_front();
+        }
+    }
+
+    /// how long the client has been sitting on unread data
+    pub fn lag(&self, now_ns: u64) -> Duration {
+        match self.unanswered_pings.front() {
+            Some(oldest) => Duration::from_nanos(now_ns.saturating_sub(*oldest)),
+            None => Duration::from_nanos(0),
+        }
+    }
+
+    pub fn snapshot_verdict(&self, now_ns: u64) -> SnapshotVerdict {
+        let lag = self.lag(now_ns);
+        if lag > self.disconnect_after {
+            SnapshotVerdict::Disconnect
+        } else if lag > self.coalesce_after {
+            SnapshotVerdict::Coalesce
+        } else {
+            SnapshotVerdict::Send
+        }
+    }
+}
+
+/// server-wide tallies of slow client handling
+#[derive(Debug, Default)]
+pub struct BackpressureCounters {
+    /// snapshots replaced by a newer one before being sent
+    pub snapshots_coalesced: AtomicU64,
+    pub slow_clients_disconnected: AtomicU64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutboundMonitor, SnapshotVerdict};
+
+    use std::time::Duration;
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn escalates_as_pings_go_unanswered() {
+        let mut monitor =
+            OutboundMonitor::new(Duration::from_millis(100), Duration::from_millis(1_000));
+        assert_eq!(monitor.snapshot_verdict(0), SnapshotVerdict::Send);
+
+        monitor.ping_sent(10 * MS);
+        assert_eq!(monitor.snapshot_verdict(100 * MS), SnapshotVerdict::Send);
+        assert_eq!(
+            monitor.snapshot_verdict(200 * MS),
+            SnapshotVerdict::Coalesce
+        );
+        assert_eq!(
+            monitor.snapshot_verdict(2_000 * MS),
+            SnapshotVerdict::Disconnect
+        );
+    }
+
+    #[test]
+    fn pong_acknowledges_earlier_pings() {
+        let mut monitor =
+            OutboundMonitor::new(Duration::from_millis(100), Duration::from_millis(1_000));
+        monitor.ping_sent(10 * MS);
+        monitor.ping_sent(20 * MS);
+        monitor.ping_sent(30 * MS);
+        monitor.pong_received(20 * MS);
+        assert_eq!(monitor.lag(50 * MS), Duration::from_millis(20));
+
+        monitor.pong_received(30 * MS);
+        assert_eq!(monitor.lag(50 * MS), Duration::from_millis(0));
+    }
+}
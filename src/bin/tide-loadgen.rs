@@ -0,0 +1,496 @@
+//! Load-tests a tide server with headless bots.
+//!
+//! usage:
+//!     tide-loadgen <ws://host:port> [options]
+//!
+//! options:
+//!     --connections <n>          bots to connect (default 10)
+//!     --duration-secs <s>        how long to run (default 30)
+//!     --position-rate <hz>       POSITION_UPDATEs per bot per second (default 20)
+//!     --projectile-rate <hz>     PROJECTILE_CREATEDs per bot per second (default 2), of the
+//!                                projectile types in the server's CATALOG
+//!     --behavior <b>             `random-walk` (default) or `scripted` (circles)
+//!     --ping-interval-ms <ms>    how often bots measure their round trip time (default 1000)
+//!     --report-secs <s>          how often to print progress (default 5)
+//!     --auth-secret <secret>     issue each bot a token, for servers which authenticate
+//!
+//! Prints how many bots connected, the rate and size of the game state snapshots
+//! they received, round trip time percentiles, and the errors they ran into.
+
+use tide::api_types as api;
+use tide::auth;
+use tide::utils;
+
+use rand::Rng;
+use ws::util::Token;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POSITION: Token = Token(1);
+const PROJECTILE: Token = Token(2);
+const PING: Token = Token(3);
+
+/// bot ids, when issuing tokens (well clear of real players')
+const FIRST_BOT_PLAYER_ID: api::PlayerId = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Behavior {
+    RandomWalk,
+    /// circles around its starting point
+    Scripted,
+}
+
+#[derive(Debug)]
+struct Options {
+    address: String,
+    connections: usize,
+    duration: Duration,
+    position_rate: f64,
+    projectile_rate: f64,
+    behavior: Behavior,
+    ping_interval_ms: u64,
+    report_interval: Duration,
+    auth_secret: Option<String>,
+}
+
+const USAGE: &str =
+    "usage: tide-loadgen <ws://host:port> [--connections <n>] [--duration-secs <s>] \
+    [--position-rate <hz>] [--projectile-rate <hz>] [--behavior random-walk|scripted] \
+    [--ping-interval-ms <ms>] [--report-secs <s>] [--auth-secret <secret>]";
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        address: args.next().ok_or(USAGE)?,
+        connections: 10,
+        duration: Duration::from_secs(30),
+        position_rate: 20.0,
+        projectile_rate: 2.0,
+        behavior: Behavior::RandomWalk,
+        ping_interval_ms: 1_000,
+        report_interval: Duration::from_secs(5),
+        auth_secret: None,
+    };
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--connections" => options.connections = number(&arg, &value?)?,
+            "--duration-secs" => options.duration = seconds(&arg, &value?)?,
+            "--position-rate" => options.position_rate = rate(&arg, &value?)?,
+            "--projectile-rate" => options.projectile_rate = rate(&arg, &value?)?,
+            "--behavior" => {
+                options.behavior = match value?.as_str() {
+                    "random-walk" => Behavior::RandomWalk,
+                    "scripted" => Behavior::Scripted,
+                    other => return Err(format!("unknown behavior [{}]", other)),
+                }
+            }
+            "--ping-interval-ms" => options.ping_interval_ms = number(&arg, &value?)?,
+            "--report-secs" => options.report_interval = interval(&arg, &value?)?,
+            "--auth-secret" => options.auth_secret = Some(value?),
+            other => return Err(format!("unrecognized argument [{}]\n{}", other, USAGE)),
+        }
+    }
+    Ok(options)
+}
+
+/// e.g. ("--connections", "200")
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("{} needs a whole number, not [{}]", flag, value))
+}
+
+fn seconds(flag: &str, value: &str) -> Result<Duration, String> {
+    let seconds: f64 = value
+        .parse()
+        .map_err(|_| format!("{} needs a number, not [{}]", flag, value))?;
+    Duration::try_from_secs_f64(seconds).map_err(|_| {
+        format!(
+            "{} needs a non-negative number of seconds, not [{}]",
+            flag, value
+        )
+    })
+}
+
+/// (unlike `seconds`, not 0)
+fn interval(flag: &str, value: &str) -> Result<Duration, String> {
+    match seconds(flag, value)? {
+        interval if !interval.is_zero() => Ok(interval),
+        _ => Err(format!(
+            "{} needs a positive number of seconds, not [{}]",
+            flag, value
+        )),
+    }
+}
+
+/// (per second, 0 for never)
+fn rate(flag: &str, value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate >= 0.0 => Ok(rate),
+        _ => Err(format!(
+            "{} needs a non-negative rate, not [{}]",
+            flag, value
+        )),
+    }
+}
+
+/// tallies across all bots
+#[derive(Debug, Default)]
+struct Stats {
+    connected: AtomicU64,
+    disconnected: AtomicU64,
+    snapshots: AtomicU64,
+    snapshot_bytes: AtomicU64,
+    largest_snapshot_bytes: AtomicU64,
+    messages_sent: AtomicU64,
+    rtts_ms: Mutex<Vec<f64>>,
+    /// by kind, e.g. "RATE_LIMITED", or "close Other(4001)"
+    errors: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Stats {
+    fn error(&self, kind: String) {
+        *self.errors.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    fn report(&self, attempted: usize, elapsed: Duration) -> String {
+        let snapshots = self.snapshots.load(Ordering::Relaxed);
+        let connected = self.connected.load(Ordering::Relaxed);
+        let secs = elapsed.as_secs_f64().max(0.001);
+        let mut rtts = self.rtts_ms.lock().unwrap().clone();
+        let disconnected = self.disconnected.load(Ordering::Relaxed);
+        // (ws only logs connections which fail to open)
+        let never_connected = (attempted as u64).saturating_sub(connected + disconnected);
+        let mut report = format!(
+            "after {:.1}s: {}/{} connected, {} since disconnected, {} not (yet) connected; {} messages sent\n",
+            secs,
+            connected,
+            attempted,
+            disconnected,
+            never_connected,
+            self.messages_sent.load(Ordering::Relaxed)
+        );
+        report += &format!(
+            "  snapshots: {:.1}/s per bot, {} bytes on average, {} at most\n",
+            snapshots as f64 / secs / (connected.max(1) as f64),
+            self.snapshot_bytes.load(Ordering::Relaxed) / snapshots.max(1),
+            self.largest_snapshot_bytes.load(Ordering::Relaxed)
+        );
+        report += &match percentiles(&mut rtts, &[50.0, 90.0, 99.0]) {
+            Some(p) => format!(
+                "  rtt: p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms ({} samples)\n",
+                p[0],
+                p[1],
+                p[2],
+                rtts.len()
+            ),
+            None => "  rtt: no samples yet\n".to_owned(),
+        };
+        let errors = self.errors.lock().unwrap();
+        if errors.is_empty() {
+            report += "  errors: none";
+        } else {
+            report += &format!("  errors: {:?}", errors);
+        }
+        report
+    }
+}
+
+/// (nearest-rank; sorts `samples`)
+fn percentiles(samples: &mut [f64], ranks: &[f64]) -> Option<Vec<f64>> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Some(
+        ranks
+            .iter()
+            .map(|rank| {
+                let index = (rank / 100.0 * samples.len() as f64).ceil() as usize;
+                samples[index.max(1).min(samples.len()) - 1]
+            })
+            .collect(),
+    )
+}
+
+struct Bot {
+    out: ws::Sender,
+    options: Arc<Options>,
+    stats: Arc<Stats>,
+    position: api::Vec2,
+    /// where a scripted bot circles around
+    center: api::Vec2,
+    started: Instant,
+    opened: bool,
+    /// the projectile types in the server's catalog (bots fire at random from these)
+    weapons: Vec<api::ProjectileType>,
+}
+
+// (returning what the handlers do, i.e. the large `ws::Error`)
+#[allow(clippy::result_large_err)]
+impl Bot {
+    fn send(&mut self, update: &api::ClientUpdate) -> ws::Result<()> {
+        self.stats.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.out.send(serde_json::ser::to_string(update).unwrap())
+    }
+
+    fn schedule(&self, rate: f64, token: Token) -> ws::Result<()> {
+        if rate > 0.0 {
+            // (a timeout of 0 would fire again right away, forever)
+            let interval_ms = (1_000.0 / rate).max(1.0);
+            self.out.timeout(interval_ms as u64, token)?;
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) {
+        let mut rng = rand::thread_rng();
+        self.position = match self.options.behavior {
+            Behavior::RandomWalk => {
+                let step = api::Vec2::new(rng.gen_range(-0.5, 0.5), rng.gen_range(-0.5, 0.5));
+                (self.position + step).map(|c| c.clamp(0.0, 100.0))
+            }
+            Behavior::Scripted => {
+                let angle = self.started.elapsed().as_secs_f32();
+                self.center + api::Vec2::new(angle.cos(), angle.sin()) * 10.0
+            }
+        };
+    }
+
+    fn now_ms() -> u64 {
+        utils::unix_time().as_millis() as u64
+    }
+}
+
+impl ws::Handler for Bot {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        self.opened = true;
+        self.stats.connected.fetch_add(1, Ordering::Relaxed);
+        self.schedule(self.options.position_rate, POSITION)?;
+        self.schedule(self.options.projectile_rate, PROJECTILE)?;
+        self.out.timeout(self.options.ping_interval_ms, PING)
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let text = msg.as_text()?;
+        if text.starts_with(api::GAME_STATE_JSON_PREFIX) {
+            let bytes = text.len() as u64;
+            self.stats.snapshots.fetch_add(1, Ordering::Relaxed);
+            self.stats
+                .snapshot_bytes
+                .fetch_add(bytes, Ordering::Relaxed);
+            self.stats
+                .largest_snapshot_bytes
+                .fetch_max(bytes, Ordering::Relaxed);
+            return Ok(());
+        }
+        let message: serde_json::Value = match serde_json::from_str(text) {
+            Ok(message) => message,
+            // (e.g. the plain text answer to a malformed message)
+            Err(_) => {
+                self.stats.error("unparseable message".to_owned());
+                return Ok(());
+            }
+        };
+        if message["type"] == "ERROR" {
+            let code = message["code"].as_str().unwrap_or("ERROR").to_owned();
+            self.stats.error(code);
+        }
+        if message["type"] == "CATALOG" {
+            if let Some(projectiles) = message["projectiles"].as_object() {
+                self.weapons = projectiles.keys().cloned().collect();
+            }
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: Token) -> ws::Result<()> {
+        match event {
+            POSITION => {
+                self.step();
+                let position = api::ClientUpdate::PositionUpdate(api::PositionStamped {
+                    xy: self.position,
+                    time_ms: Bot::now_ms(),
+                });
+                self.send(&position)?;
+                self.schedule(self.options.position_rate, POSITION)
+            }
+            // (nothing to fire until the catalog arrives)
+            PROJECTILE if self.weapons.is_empty() => {
+                self.schedule(self.options.projectile_rate, PROJECTILE)
+            }
+            PROJECTILE => {
+                let mut rng = rand::thread_rng();
+                let angle: f32 = rng.gen_range(0.0, std::f32::consts::PI * 2.0);
+                let weapon = &self.weapons[rng.gen_range(0, self.weapons.len())];
+                let projectile = api::ClientUpdate::ProjectileCreated(api::ProjectileSnaphot {
+                    projectile_type: weapon.clone(),
+                    origin: api::PositionStamped {
+                        xy: self.position,
+                        time_ms: Bot::now_ms(),
+                    },
+                    vel: api::Vec2::new(angle.cos(), angle.sin()),
+                });
+                self.send(&projectile)?;
+                self.schedule(self.options.projectile_rate, PROJECTILE)
+            }
+            PING => {
+                self.out.ping(utils::custom_time_ns().to_string().into())?;
+                self.out.timeout(self.options.ping_interval_ms, PING)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn on_frame(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        if frame.opcode() == ws::OpCode::Pong {
+            let sent_ns = std::str::from_utf8(frame.payload())
+                .ok()
+                .and_then(|payload| payload.parse::<u64>().ok());
+            if let Some(rtt_ns) = sent_ns.and_then(|sent| utils::custom_time_ns().checked_sub(sent))
+            {
+                self.stats
+                    .rtts_ms
+                    .lock()
+                    .unwrap()
+                    .push(rtt_ns as f64 / 1_000_000f64);
+            }
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, code: ws::CloseCode, _: &str) {
+        if self.opened {
+            self.stats.connected.fetch_sub(1, Ordering::Relaxed);
+            self.stats.disconnected.fetch_add(1, Ordering::Relaxed);
+        }
+        if code != ws::CloseCode::Normal && code != ws::CloseCode::Away {
+            self.stats.error(format!("close {:?}", code));
+        }
+    }
+
+    fn on_error(&mut self, error: ws::Error) {
+        self.stats.error(format!("{:?}", error.kind));
+    }
+}
+
+fn bot_url(options: &Options, i: usize) -> Result<url::Url, String> {
+    let mut url = url::Url::parse(&options.address)
+        .map_err(|error| format!("bad address [{}]: {}", options.address, error))?;
+    if let Some(secret) = &options.auth_secret {
+        let claims = auth::Claims {
+            player_id: FIRST_BOT_PLAYER_ID + i as api::PlayerId,
+            display_name: format!("bot-{}", i),
+            role: api::Role::Player,
+            exp: utils::unix_time().as_secs() + options.duration.as_secs() + 3_600,
+        };
+        url.query_pairs_mut().append_pair(
+            auth::TOKEN_QUERY_PARAM,
+            &auth::issue_token(secret.as_bytes(), &claims),
+        );
+    }
+    Ok(url)
+}
+
+fn main() {
+    env_logger::init();
+    let options = Arc::new(parse_args().unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(2)
+    }));
+    let stats = Arc::new(Stats::default());
+
+    let factory_options = options.clone();
+    let factory_stats = stats.clone();
+    let mut socket = ws::Builder::new()
+        .with_settings(ws::Settings {
+            max_connections: options.connections + 1,
+            ..ws::Settings::default()
+        })
+        .build(move |out: ws::Sender| {
+            let mut rng = rand::thread_rng();
+            let start = api::Vec2::new(rng.gen_range(10.0, 90.0), rng.gen_range(10.0, 90.0));
+            Bot {
+                out,
+                options: factory_options.clone(),
+                stats: factory_stats.clone(),
+                position: start,
+                center: start,
+                started: Instant::now(),
+                opened: false,
+                weapons: vec![],
+            }
+        })
+        .unwrap_or_else(|error| {
+            eprintln!("cannot set up websockets: {}", error);
+            std::process::exit(1)
+        });
+    for i in 0..options.connections {
+        let url = bot_url(&options, i).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(2)
+        });
+        if let Err(error) = socket.connect(url) {
+            stats.error(format!("{:?}", error.kind));
+        }
+    }
+
+    // report as we go, then close every bot once the time's up
+    let broadcaster = socket.broadcaster();
+    let reporter_options = options.clone();
+    let reporter_stats = stats.clone();
+    let started = Instant::now();
+    let reporter = thread::spawn(move || {
+        while started.elapsed() < reporter_options.duration {
+            let remaining = reporter_options.duration.saturating_sub(started.elapsed());
+            thread::sleep(reporter_options.report_interval.min(remaining));
+            println!(
+                "{}",
+                reporter_stats.report(reporter_options.connections, started.elapsed())
+            );
+        }
+        let _ = broadcaster.close(ws::CloseCode::Normal);
+        thread::sleep(Duration::from_millis(500));
+        let _ = broadcaster.shutdown();
+    });
+
+    if let Err(error) = socket.run() {
+        eprintln!("websockets event loop failed: {}", error);
+    }
+    reporter.join().unwrap();
+    println!(
+        "final:\n{}",
+        stats.report(options.connections, started.elapsed())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{interval, percentiles};
+    use std::time::Duration;
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let mut samples: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        assert_eq!(
+            percentiles(&mut samples, &[50.0, 90.0, 99.0, 100.0]),
+            Some(vec![50.0, 90.0, 99.0, 100.0])
+        );
+        assert_eq!(percentiles(&mut [], &[50.0]), None);
+    }
+
+    #[test]
+    fn intervals_must_be_positive() {
+        assert_eq!(
+            interval("--report-secs", "0.5"),
+            Ok(Duration::from_millis(500))
+        );
+        assert!(interval("--report-secs", "0").is_err());
+        assert!(interval("--report-secs", "-1").is_err());
+    }
+}
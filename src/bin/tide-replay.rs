@@ -0,0 +1,112 @@
+//! Replays a match recording (see `recording`), checking that every tick
+//! reproduces the recorded game state.
+//!
+//! usage:
+//!     tide-replay <recording> [--dump <tick>]...
+//!
+//! `--dump` prints the replayed state at that tick (as a json line), along with
+//! how it differs from the original run's, if the recording has a snapshot of it.
+//! Exits with status 1 if the replay diverged from the recording.
+
+use tide::config;
+use tide::game_control::GameController;
+use tide::recording;
+
+use std::collections::HashSet;
+
+fn parse_args() -> Result<(String, HashSet<u64>), String> {
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .ok_or("usage: tide-replay <recording> [--dump <tick>]...")?;
+    let mut dump_ticks = HashSet::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dump" => {
+                let tick = args
+                    .next()
+                    .and_then(|tick| tick.parse().ok())
+                    .ok_or("--dump needs a tick number")?;
+                dump_ticks.insert(tick);
+            }
+            other => return Err(format!("unrecognized argument [{}]", other)),
+        }
+    }
+    Ok((path, dump_ticks))
+}
+
+fn main() {
+    env_logger::init();
+    match config::from_env() {
+        Ok(replay_config) => config::install(replay_config),
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(2)
+        }
+    }
+    let (path, dump_ticks) = parse_args().unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(2)
+    });
+    let recording = recording::read(&path).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(2)
+    });
+
+    let header = &recording.header;
+    let known: Vec<(&str, u64)> = header
+        .tunables
+        .iter()
+        .filter(|(name, _)| {
+            let known = config::tunables()
+                .iter()
+                .any(|(tunable, _)| tunable == name);
+            if !known {
+                eprintln!("ignoring recorded tunable [{}], no such tunable", name);
+            }
+            known
+        })
+        .map(|(name, value)| (name.as_str(), *value))
+        .collect();
+    // (together, as each alone might not fit the others' current values)
+    if let Err(error) = config::set_tunables(&known) {
+        eprintln!("ignoring recorded tunables: {}", error);
+    }
+    let mut game = GameController::replaying(header.seed, header.map_max_dimension);
+    if let Some(catalog) = &header.catalog {
+        game.set_catalog(catalog.clone());
+    }
+    let tick_count = recording.ticks.len();
+    let mut diverged: Vec<u64> = vec![];
+    let replayed = recording::replay(&mut game, recording.ticks, |record, state| {
+        let matches = recording::digest(state) == record.digest;
+        if !matches {
+            if diverged.is_empty() {
+                eprintln!("tick {} diverged from the recording", record.tick);
+            }
+            diverged.push(record.tick);
+        }
+        if dump_ticks.contains(&record.tick) {
+            println!(
+                "{}",
+                serde_json::json!({"tick": record.tick, "state": state})
+            );
+        }
+        if let Some(original) = &record.snapshot {
+            if !matches || dump_ticks.contains(&record.tick) {
+                for difference in recording::diff(original, state) {
+                    eprintln!("tick {}: {}", record.tick, difference);
+                }
+            }
+        }
+    });
+    if let Err(error) = replayed {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+
+    eprintln!("replayed {} ticks, {} diverged", tick_count, diverged.len());
+    if !diverged.is_empty() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,639 @@
+use crate::admin;
+use crate::api_types as api;
+use crate::auth;
+use crate::clock::{Clock, SystemClock};
+use crate::server;
+
+use ws::util::Token;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/* A typed client for the tide protocol (see `api_types`), for bots and tests.
+
+usage:
+    let mut client = Client::connect("ws://localhost:3012", ConnectOptions::default())?;
+    client.send_position(api::Vec2::new(1.0, 2.0))?;
+    for event in client.events() {
+        match event {
+            Event::GameState(state) => ...,
+            Event::Update(api::ServerUpdate::Error(error)) => ...,
+            ...
+        }
+    }
+
+The connection runs on its own thread; `connect` returns once the server has
+welcomed us (YOUR_PLAYER_ID, or SPECTATING when spectating), and everything the
+server sends after that is read, in order, from the events. The server's
+CATALOG (which projectile types there are) is kept, see `catalog`.
+
+Time sync: the client pings the server to measure round trip times, and the
+server's own pings carry its clock reading, so together they estimate how far
+the server's clock is from ours. Messages are timestamped in server time.
+*/
+
+const PING: Token = Token(1);
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// auth token, for servers which authenticate (see `auth::issue_token`)
+    pub token: Option<String>,
+    /// from an earlier YOUR_PLAYER_ID, to resume that session
+    pub resume_token: Option<String>,
+    /// how long to wait for the server to welcome us
+    pub handshake_timeout: Duration,
+    /// how often to measure the round trip time
+    pub ping_interval: Duration,
+    /// what "our" time is (e.g. a `ManualClock` shared with a test server)
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> ConnectOptions {
+        ConnectOptions {
+            token: None,
+            resume_token: None,
+            handshake_timeout: Duration::from_secs(5),
+            ping_interval: Duration::from_secs(1),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    BadAddress(String),
+    Websocket(Box<ws::Error>),
+    /// the server didn't welcome us within `ConnectOptions::handshake_timeout`
+    HandshakeTimedOut,
+    /// e.g. the server refused the handshake, or kicked us
+    Closed { code: ws::CloseCode, reason: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::BadAddress(details) => write!(f, "bad address: {}", details),
+            ClientError::Websocket(error) => write!(f, "websocket error: {}", error),
+            ClientError::HandshakeTimedOut => write!(f, "the server didn't welcome us in time"),
+            ClientError::Closed { code, reason } => {
+                write!(f, "connection closed ({:?}): [{}]", code, reason)
+            }
+        }
+    }
+}
+
+impl From<ws::Error> for ClientError {
+    fn from(error: ws::Error) -> ClientError {
+        ClientError::Websocket(Box::new(error))
+    }
+}
+
+/// how the server greeted us
+#[derive(Debug, Clone, PartialEq)]
+pub enum Welcome {
+    Player {
+        player_id: api::PlayerId,
+        resume_token: String,
+    },
+    Spectator {
+        spectator_id: u32,
+        following: Option<api::PlayerId>,
+    },
+    /// the admin endpoint doesn't greet anyone
+    Admin,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    GameState(api::GameState),
+    Update(api::ServerUpdate),
+    /// not a server message we know (e.g. the plain text answer to a malformed message)
+    Unrecognized(String),
+    /// the last event
+    Closed { code: ws::CloseCode, reason: String },
+}
+
+/// tells snapshots apart from other server messages (which are tagged with "type")
+pub fn decode(text: &str) -> Event {
+    let decoded = if text.starts_with(api::GAME_STATE_JSON_PREFIX) {
+        serde_json::from_str(text).map(Event::GameState)
+    } else {
+        serde_json::from_str(text).map(Event::Update)
+    };
+    decoded.unwrap_or_else(|_| Event::Unrecognized(text.to_owned()))
+}
+
+/// Estimates of the round trip time, and of the server's clock relative to ours.
+#[derive(Debug, Clone, Default)]
+pub struct TimeSync {
+    rtt_ms: Option<f64>,
+    /// server clock minus ours
+    offset_ms: Option<f64>,
+}
+
+/// weight of each new sample (as in TCP's smoothed round trip time)
+const SMOOTHING: f64 = 0.125;
+
+fn smooth(estimate: Option<f64>, sample: f64) -> Option<f64> {
+    Some(match estimate {
+        Some(estimate) => estimate + SMOOTHING * (sample - estimate),
+        None => sample,
+    })
+}
+
+impl TimeSync {
+    pub fn pong_received(&mut self, rtt: Duration) {
+        self.rtt_ms = smooth(self.rtt_ms, rtt.as_secs_f64() * 1_000.0);
+    }
+
+    /// both in ns since `utils::CUSTOM_EPOCH`; the server's ping left it half a round trip ago
+    pub fn server_ping_received(&mut self, server_sent_ns: u64, local_received_ns: u64) {
+        let one_way_ms = self.rtt_ms.unwrap_or(0.0) / 2.0;
+        let sample = (server_sent_ns as f64 - local_received_ns as f64) / 1_000_000.0 + one_way_ms;
+        self.offset_ms = smooth(self.offset_ms, sample);
+    }
+
+    /// None until a ping has been answered
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt_ms.map(|ms| Duration::from_secs_f64(ms / 1_000.0))
+    }
+
+    /// None until the server has pinged us
+    pub fn offset_ms(&self) -> Option<f64> {
+        self.offset_ms
+    }
+
+    /// our clock reading, in server time (as is, until there's an estimate)
+    pub fn server_time_ms(&self, local_ms: u64) -> u64 {
+        (local_ms as f64 + self.offset_ms.unwrap_or(0.0)).max(0.0) as u64
+    }
+}
+
+/// what the connection thread passes on
+enum Incoming {
+    Opened(ws::Sender),
+    Event(Event),
+}
+
+/// ws handler, on the connection thread
+struct Connection {
+    out: ws::Sender,
+    incoming: Sender<Incoming>,
+    time: Arc<Mutex<TimeSync>>,
+    clock: Arc<dyn Clock>,
+    ping_interval_ms: u64,
+}
+
+impl ws::Handler for Connection {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        let _ = self.incoming.send(Incoming::Opened(self.out.clone()));
+        self.out.timeout(self.ping_interval_ms, PING)
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let _ = self.incoming.send(Incoming::Event(decode(msg.as_text()?)));
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: Token) -> ws::Result<()> {
+        if event == PING {
+            self.out.ping(self.clock.custom_time_ns().to_string().into())?;
+            self.out.timeout(self.ping_interval_ms, PING)?;
+        }
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        // both kinds of payload are clock readings (ours in pongs, the server's in pings)
+        let payload = std::str::from_utf8(frame.payload())
+            .ok()
+            .and_then(|payload| payload.parse::<u64>().ok());
+        if let Some(sent_ns) = payload {
+            let now_ns = self.clock.custom_time_ns();
+            let mut time = self.time.lock().unwrap();
+            match frame.opcode() {
+                ws::OpCode::Pong => {
+                    if let Some(rtt_ns) = now_ns.checked_sub(sent_ns) {
+                        time.pong_received(Duration::from_nanos(rtt_ns));
+                    }
+                }
+                ws::OpCode::Ping => time.server_ping_received(sent_ns, now_ns),
+                _ => {}
+            }
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
+        let _ = self.incoming.send(Incoming::Event(Event::Closed {
+            code,
+            reason: reason.to_owned(),
+        }));
+        // (the event loop only runs this connection)
+        let _ = self.out.shutdown();
+    }
+
+    fn on_error(&mut self, error: ws::Error) {
+        warn!("client connection error: {}", error);
+    }
+}
+
+/// the url to connect to, with the tokens from `options`
+pub fn url(address: &str, options: &ConnectOptions) -> Result<url::Url, ClientError> {
+    let mut url = url::Url::parse(address)
+        .map_err(|error| ClientError::BadAddress(format!("[{}]: {}", address, error)))?;
+    if let Some(token) = &options.token {
+        url.query_pairs_mut()
+            .append_pair(auth::TOKEN_QUERY_PARAM, token);
+    }
+    if let Some(resume_token) = &options.resume_token {
+        url.query_pairs_mut()
+            .append_pair(server::RESUME_TOKEN_QUERY_PARAM, resume_token);
+    }
+    Ok(url)
+}
+
+pub struct Client {
+    out: ws::Sender,
+    incoming: Receiver<Incoming>,
+    /// received while waiting to be welcomed
+    pending: VecDeque<Event>,
+    welcome: Welcome,
+    /// the latest CATALOG (the server sends one upon connecting)
+    catalog: Option<api::Catalog>,
+    time: Arc<Mutex<TimeSync>>,
+    clock: Arc<dyn Clock>,
+    event_loop: Option<JoinHandle<()>>,
+    closed: bool,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("welcome", &self.welcome)
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+/// how `Client::connect` was answered
+struct Handshake {
+    out: ws::Sender,
+    welcome: Welcome,
+    /// events received meanwhile
+    pending: VecDeque<Event>,
+}
+
+impl Client {
+    /// Connects to `address` (e.g. "ws://localhost:3012", or ".../spectate"), and
+    /// waits for the server to welcome us.
+    pub fn connect(address: &str, options: ConnectOptions) -> Result<Client, ClientError> {
+        let url = url(address, &options)?;
+        let is_admin = url.path() == admin::ADMIN_PATH;
+        let (incoming_tx, incoming) = mpsc::channel();
+        let time = Arc::new(Mutex::new(TimeSync::default()));
+
+        let handler_time = time.clone();
+        let handler_clock = options.clock.clone();
+        let ping_interval_ms = options.ping_interval.as_millis() as u64;
+        let mut socket = ws::Builder::new().build(move |out: ws::Sender| Connection {
+            out,
+            incoming: incoming_tx.clone(),
+            time: handler_time.clone(),
+            clock: handler_clock.clone(),
+            ping_interval_ms,
+        })?;
+        socket.connect(url)?;
+        let broadcaster = socket.broadcaster();
+        let event_loop = thread::Builder::new()
+            .name("tide client".to_owned())
+            .spawn(move || {
+                if let Err(error) = socket.run() {
+                    warn!("client event loop failed: {}", error);
+                }
+            })
+            .map_err(ws::Error::from)?;
+
+        // (failed connections are only logged by ws, so this is also how those end)
+        let deadline = Instant::now() + options.handshake_timeout;
+        let handshake = Client::handshake(&incoming, deadline, is_admin);
+        let handshake = match handshake {
+            Ok(handshake) => handshake,
+            Err(error) => {
+                let _ = broadcaster.shutdown();
+                let _ = event_loop.join();
+                return Err(error);
+            }
+        };
+        let catalog = handshake.pending.iter().rev().find_map(|event| match event {
+            Event::Update(api::ServerUpdate::Catalog(catalog)) => Some(catalog.clone()),
+            _ => None,
+        });
+        Ok(Client {
+            out: handshake.out,
+            incoming,
+            pending: handshake.pending,
+            welcome: handshake.welcome,
+            catalog,
+            time,
+            clock: options.clock,
+            event_loop: Some(event_loop),
+            closed: false,
+        })
+    }
+
+    fn handshake(
+        incoming: &Receiver<Incoming>,
+        deadline: Instant,
+        is_admin: bool,
+    ) -> Result<Handshake, ClientError> {
+        let mut out = None;
+        let mut pending = VecDeque::new();
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let received = incoming.recv_timeout(timeout).map_err(|error| match error {
+                RecvTimeoutError::Timeout => ClientError::HandshakeTimedOut,
+                RecvTimeoutError::Disconnected => ClientError::Closed {
+                    code: ws::CloseCode::Abnormal,
+                    reason: "connection thread ended".to_owned(),
+                },
+            })?;
+            match received {
+                Incoming::Opened(out) if is_admin => {
+                    let welcome = Welcome::Admin;
+                    return Ok(Handshake {
+                        out,
+                        welcome,
+                        pending,
+                    });
+                }
+                Incoming::Opened(sender) => out = Some(sender),
+                Incoming::Event(Event::Closed { code, reason }) => {
+                    return Err(ClientError::Closed { code, reason });
+                }
+                Incoming::Event(Event::Update(api::ServerUpdate::YourPlayerId(message))) => {
+                    let welcome = Welcome::Player {
+                        player_id: message.player_id,
+                        resume_token: message.resume_token,
+                    };
+                    let out = out.unwrap();
+                    return Ok(Handshake {
+                        out,
+                        welcome,
+                        pending,
+                    });
+                }
+                Incoming::Event(Event::Update(api::ServerUpdate::Spectating(message))) => {
+                    let welcome = Welcome::Spectator {
+                        spectator_id: message.spectator_id,
+                        following: message.following,
+                    };
+                    let out = out.unwrap();
+                    return Ok(Handshake {
+                        out,
+                        welcome,
+                        pending,
+                    });
+                }
+                // (e.g. a snapshot broadcast before we were welcomed)
+                Incoming::Event(event) => pending.push_back(event),
+            }
+        }
+    }
+
+    pub fn welcome(&self) -> &Welcome {
+        &self.welcome
+    }
+
+    /// None for spectators and admins
+    pub fn player_id(&self) -> Option<api::PlayerId> {
+        match &self.welcome {
+            Welcome::Player { player_id, .. } => Some(*player_id),
+            _ => None,
+        }
+    }
+
+    /// reconnect with this (as `ConnectOptions::resume_token`) to resume the session
+    pub fn resume_token(&self) -> Option<&str> {
+        match &self.welcome {
+            Welcome::Player { resume_token, .. } => Some(resume_token),
+            _ => None,
+        }
+    }
+
+    /// what the server's projectiles and enemies are like (None from the admin endpoint)
+    pub fn catalog(&self) -> Option<&api::Catalog> {
+        self.catalog.as_ref()
+    }
+
+    pub fn time_sync(&self) -> TimeSync {
+        self.time.lock().unwrap().clone()
+    }
+
+    /// our best guess at the server's clock (what messages are stamped with)
+    pub fn server_time_ms(&self) -> u64 {
+        self.time_sync().server_time_ms(self.clock.now_ms())
+    }
+
+    fn send_json<T: serde::Serialize>(&self, message: &T) -> Result<(), ClientError> {
+        self.out.send(serde_json::ser::to_string(message).unwrap())?;
+        Ok(())
+    }
+
+    /// as is, e.g. to see how the server takes a malformed message
+    pub fn send_text(&self, text: &str) -> Result<(), ClientError> {
+        self.out.send(text)?;
+        Ok(())
+    }
+
+    /// an unsolicited pong (which the server takes as answering its ping, if `data` says so)
+    pub fn send_pong(&self, data: &str) -> Result<(), ClientError> {
+        self.out.pong(data.as_bytes().to_vec())?;
+        Ok(())
+    }
+
+    pub fn send(&self, update: &api::ClientUpdate) -> Result<(), ClientError> {
+        self.send_json(update)
+    }
+
+    pub fn send_position(&self, xy: api::Vec2) -> Result<(), ClientError> {
+        self.send(&api::ClientUpdate::PositionUpdate(api::PositionStamped {
+            xy,
+            time_ms: self.server_time_ms(),
+        }))
+    }
+
+    /// `projectile_type` is one of the catalog's (see `catalog`); `direction` needn't be normalized
+    pub fn fire_projectile(
+        &self,
+        projectile_type: &str,
+        from: api::Vec2,
+        direction: api::Vec2,
+    ) -> Result<(), ClientError> {
+        self.send(&api::ClientUpdate::ProjectileCreated(api::ProjectileSnaphot {
+            projectile_type: projectile_type.to_owned(),
+            origin: api::PositionStamped {
+                xy: from,
+                time_ms: self.server_time_ms(),
+            },
+            vel: direction,
+        }))
+    }
+
+    /// (spectators) whose viewpoint to watch from
+    pub fn follow(&self, player_id: Option<api::PlayerId>) -> Result<(), ClientError> {
+        self.send_json(&api::SpectatorCommand::Follow { player_id })
+    }
+
+    /// (on the admin endpoint)
+    pub fn admin(&self, command: &api::AdminCommand) -> Result<(), ClientError> {
+        self.send_json(command)
+    }
+
+    fn received(&mut self, incoming: Incoming) -> Option<Event> {
+        match incoming {
+            Incoming::Event(event) => {
+                match &event {
+                    Event::Closed { .. } => self.closed = true,
+                    Event::Update(api::ServerUpdate::Catalog(catalog)) => {
+                        self.catalog = Some(catalog.clone())
+                    }
+                    _ => {}
+                }
+                Some(event)
+            }
+            Incoming::Opened(_) => None,
+        }
+    }
+
+    /// waits for the next event; None once the connection has closed
+    pub fn next_event(&mut self) -> Option<Event> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        while !self.closed {
+            let incoming = self.incoming.recv().ok()?;
+            if let Some(event) = self.received(incoming) {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    /// None if there wasn't one within `timeout` (or the connection has closed)
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        let deadline = Instant::now() + timeout;
+        while !self.closed {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let incoming = self.incoming.recv_timeout(remaining).ok()?;
+            if let Some(event) = self.received(incoming) {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    /// every event until the connection closes (including the `Event::Closed`)
+    pub fn events(&mut self) -> Events<'_> {
+        Events { client: self }
+    }
+
+    /// closes the connection, and waits for its thread to finish
+    pub fn close(mut self) {
+        self.shut_down();
+    }
+
+    fn shut_down(&mut self) {
+        if let Some(event_loop) = self.event_loop.take() {
+            if !self.closed {
+                let _ = self.out.close(ws::CloseCode::Normal);
+            }
+            // (in case the server doesn't answer the close)
+            let _ = self.out.shutdown();
+            let _ = event_loop.join();
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+pub struct Events<'c> {
+    client: &'c mut Client,
+}
+
+impl<'c> Iterator for Events<'c> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.client.next_event()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, url, ConnectOptions, Event, TimeSync};
+    use crate::api_types as api;
+
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn decodes_snapshots_and_updates() {
+        let state = api::GameState {
+            players: HashMap::new(),
+            enemies: vec![],
+            projectiles: vec![],
+            paused: true,
+        };
+        match decode(&serde_json::ser::to_string(&state).unwrap()) {
+            Event::GameState(decoded) => assert!(decoded.paused),
+            other => panic!("expected game state, got {:?}", other),
+        }
+        let update = api::ServerUpdate::Error(api::ErrorMessage {
+            code: api::ErrorCode::RateLimited,
+            message: "slow down".to_owned(),
+        });
+        match decode(&serde_json::ser::to_string(&update).unwrap()) {
+            Event::Update(api::ServerUpdate::Error(error)) => assert_eq!(error.message, "slow down"),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(matches!(decode("bad message"), Event::Unrecognized(_)));
+    }
+
+    #[test]
+    fn time_sync_allows_for_half_the_round_trip() {
+        let mut time = TimeSync::default();
+        assert_eq!(time.server_time_ms(1_000), 1_000);
+        time.pong_received(Duration::from_millis(40));
+        // the server's ping was sent at 500ms (its time), and arrived at 100ms (ours)
+        time.server_ping_received(500_000_000, 100_000_000);
+        assert_eq!(time.offset_ms(), Some(420.0));
+        assert_eq!(time.server_time_ms(1_000), 1_420);
+        assert_eq!(time.rtt(), Some(Duration::from_millis(40)));
+    }
+
+    #[test]
+    fn urls_carry_the_tokens() {
+        let options = ConnectOptions {
+            token: Some("abc.def".to_owned()),
+            resume_token: Some("1.xyz".to_owned()),
+            ..ConnectOptions::default()
+        };
+        let spectating = url("ws://localhost:3012/spectate", &options).unwrap();
+        assert_eq!(spectating.path(), "/spectate");
+        assert_eq!(spectating.query(), Some("token=abc.def&resumeToken=1.xyz"));
+        assert!(url("not an address", &options).is_err());
+    }
+}
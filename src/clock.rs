@@ -0,0 +1,84 @@
+use crate::utils;
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Where the game controller and the server's ping logic get the time from,
+/// so that tests (and replays) can control it rather than wait for it.
+pub trait Clock: Debug + Send + Sync {
+    /// since the unix epoch
+    fn now(&self) -> Duration;
+
+    fn now_ms(&self) -> u64 {
+        self.now().as_millis() as u64
+    }
+
+    /// since `utils::CUSTOM_EPOCH` (e.g. in ping payloads)
+    fn custom_time_ns(&self) -> u64 {
+        self.now()
+            .checked_sub(utils::CUSTOM_EPOCH_OFFSET)
+            .unwrap_or_default()
+            .as_nanos() as u64
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        utils::unix_time()
+    }
+}
+
+/// Only moves when told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    now_ns: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now: Duration) -> ManualClock {
+        ManualClock {
+            now_ns: AtomicU64::new(now.as_nanos() as u64),
+        }
+    }
+
+    pub fn set(&self, now: Duration) {
+        self.now_ns.store(now.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now_ns
+            .fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.now_ns.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, ManualClock};
+    use crate::utils;
+
+    use std::time::Duration;
+
+    #[test]
+    fn manual_clock_moves_only_when_told() {
+        let clock = ManualClock::new(utils::CUSTOM_EPOCH_OFFSET + Duration::from_millis(5));
+        assert_eq!(clock.custom_time_ns(), 5_000_000);
+        clock.advance(Duration::from_millis(20));
+        assert_eq!(clock.custom_time_ns(), 25_000_000);
+        assert_eq!(clock.now_ms(), clock.now_ms());
+
+        clock.set(Duration::from_secs(1));
+        assert_eq!(clock.now_ms(), 1_000);
+        // (before the custom epoch)
+        assert_eq!(clock.custom_time_ns(), 0);
+    }
+}
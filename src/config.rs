@@ -1,71 +1,599 @@
 #![allow(non_snake_case)]
 
+use serde::{Deserialize, Serialize};
+
 use std::env;
-use std::time::Duration;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/* Server configuration, in layers (each overriding the one before):
+    1. defaults (below)
+    2. a toml file, given with `--config <path>` (or CONFIG_PATH):
+           max_players = 50
+           rate_limit_projectiles_per_sec = 10
+    3. environment variables, named as the settings' accessors:
+           MAX_PLAYERS=50
+    4. command line flags:
+           tide 0.0.0.0:3012 --max-players 50
+
+The loaded `ServerConfig` is validated, then installed (see `install`), after
+which every setting is read through its accessor, e.g. `config::MAX_PLAYERS()`.
+Tunables may also change while running (see `set_tunables`).
+*/
+
+/// text a setting may be given as (in the environment, or on the command line)
+trait Setting: Sized {
+    fn parse_setting(value: &str) -> Result<Self, String>;
+}
+
+macro_rules! whole_number_setting {
+    ($($type:ty),*) => {
+        $(impl Setting for $type {
+            fn parse_setting(value: &str) -> Result<$type, String> {
+                value
+                    .parse()
+                    .map_err(|_| format!("expected a whole number, got [{}]", value))
+            }
+        })*
+    };
+}
 
-/// must be called *synchronously* before accessing any environment variables
-/// (access is not synchronized)
-pub fn init() {
-    env_logger::init();
-    init_env_vars();
+whole_number_setting!(u32, u64, usize);
+
+impl Setting for String {
+    fn parse_setting(value: &str) -> Result<String, String> {
+        Ok(value.to_owned())
+    }
+}
+
+impl Setting for Option<String> {
+    fn parse_setting(value: &str) -> Result<Option<String>, String> {
+        Ok(Some(value.to_owned()))
+    }
 }
 
-macro_rules! define_env_var {
-    ($name:ident, $type:ty, $default_value:expr) => {
-        /// define the value in its own module (for name deconfliction)
+/// Like `define_setting`, but may also be changed while running (see `set_tunables`).
+/// Whoever reads it picks up the change from then on.
+macro_rules! define_tunable {
+    ($name:ident, $default_value:expr) => {
         mod $name {
-            #[allow(unused_imports)]
-            use ::std::time::Duration;
-            pub static mut $name: $type = $default_value;
+            pub static $name: super::AtomicU64 = super::AtomicU64::new($default_value);
         }
 
-        /// gets the static value
+        /// gets the current value
+        pub fn $name() -> u64 {
+            $name::$name.load(Ordering::Relaxed)
+        }
+    };
+}
+
+/// the accessor for a setting, by its kind: `setting`, `secret` (never logged) or `tunable`
+macro_rules! define_setting {
+    (tunable, $field:ident, $name:ident, $type:ty, $default_value:expr) => {
+        define_tunable!($name, $default_value);
+    };
+    ($kind:ident, $field:ident, $name:ident, $type:ty, $default_value:expr) => {
+        /// gets the installed value
         pub fn $name() -> $type {
-            unsafe { $name::$name }
+            CURRENT.read().unwrap().$field.clone()
         }
     };
 }
 
-macro_rules! init_env_var_impl {
-    ($name:ident, $parse_closure:expr) => {{
-        match env::var(stringify!($name)) {
-            Ok(value) => {
-                $name::$name = Duration::from_millis(value.parse().unwrap());
-                info!("{}=[{:?}]", stringify!($name), &$name::$name);
+macro_rules! install_setting {
+    (tunable, $field:ident, $name:ident, $config:expr) => {
+        $name::$name.store($config.$field, Ordering::Relaxed)
+    };
+    ($kind:ident, $field:ident, $name:ident, $config:expr) => {};
+}
+
+macro_rules! show_setting {
+    (secret, $value:expr) => {
+        if $value.is_some() { "(set)" } else { "(not set)" }.to_owned()
+    };
+    ($kind:ident, $value:expr) => {
+        format!("{:?}", $value)
+    };
+}
+
+/// generates `ServerConfig` (with its defaults), and every setting's accessor
+macro_rules! settings {
+    ($($(#[doc = $doc:literal])* $kind:ident $field:ident / $name:ident: $type:ty = $default_value:expr;)*) => {
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct ServerConfig {
+            $($(#[doc = $doc])* pub $field: $type,)*
+        }
+
+        impl Default for ServerConfig {
+            fn default() -> ServerConfig {
+                ServerConfig {
+                    $($field: $default_value,)*
+                }
             }
-            Err(_) => {
-                warn!("{} using default [{:?}]", stringify!($name), &$name::$name);
+        }
+
+        $(define_setting!($kind, $field, $name, $type, $default_value);)*
+
+        /// (toml key, accessor and environment variable, help)
+        const SETTINGS: &[(&str, &str, &str)] = &[
+            $((stringify!($field), stringify!($name), concat!($($doc, "\n",)*)),)*
+        ];
+
+        impl ServerConfig {
+            /// sets the setting named `name` (as its accessor) from text; None if there's no such setting
+            fn set(&mut self, name: &str, value: &str) -> Option<Result<(), String>> {
+                match name {
+                    $(stringify!($name) => {
+                        Some(Setting::parse_setting(value).map(|parsed| self.$field = parsed))
+                    })*
+                    _ => None,
+                }
+            }
+
+            /// every setting and its value, for logging (secrets only say whether they're set)
+            pub fn summary(&self) -> Vec<(&'static str, String)> {
+                vec![$((stringify!($name), show_setting!($kind, self.$field)),)*]
+            }
+
+            fn install_tunables(&self) {
+                $(install_setting!($kind, $field, $name, self);)*
             }
         }
-    }};
+    };
+}
+
+settings! {
+    /// where to listen for players (and probes, and scrapers)
+    setting listen_address / LISTEN_ADDRESS: String = "127.0.0.1:3012".to_owned();
+    /// the game controller ticks at least this often (it waits this long for client messages)
+    setting await_client_msg_timeout_ms / AWAIT_CLIENT_MSG_TIMEOUT_MS: u64 = 50;
+    /// a toml file describing the map (e.g. `max_dimension = 100`), instead of `map_max_dimension`
+    setting map_path / MAP_PATH: Option<String> = None;
+    /// the map is this many units across, when there's no `map_path`
+    setting map_max_dimension / MAP_MAX_DIMENSION: usize = 100;
+    /// a toml file of projectile and enemy stats and spawn rates (see `gameplay`), reloaded
+    /// upon SIGHUP or when it changes; the built-in catalog when unset
+    setting gameplay_path / GAMEPLAY_PATH: Option<String> = None;
+    /// players beyond this many are turned away (spectators and admins aren't counted)
+    setting max_players / MAX_PLAYERS: usize = 100;
+    /// how often connections are pinged (for heartbeats and round trip times)
+    setting websockets_pingpong_interval_ms / WEBSOCKETS_PINGPONG_INTERVAL_MS: u64 = 4_500;
+    /// connections which don't answer pings for this many intervals are considered dead and closed
+    setting heartbeat_missed_intervals / HEARTBEAT_MISSED_INTERVALS: u32 = 3;
+    /// how long to let clients receive the shutdown message and close frame
+    /// before the event loop is stopped
+    setting shutdown_drain_ms / SHUTDOWN_DRAIN_MS: u64 = 1_000;
+    /// how long a disconnected player may reconnect (with their resume token) as themselves
+    setting resume_grace_period_ms / RESUME_GRACE_PERIOD_MS: u64 = 30_000;
+    /// shared secret for verifying the auth tokens presented at handshake;
+    /// if not set, anyone may connect (development only)
+    secret auth_secret / AUTH_SECRET: Option<String> = None;
+    /// per-connection rate limits (token buckets: sustained rate, and burst),
+    /// changes apply to connections opened afterwards
+    tunable rate_limit_position_updates_per_sec / RATE_LIMIT_POSITION_UPDATES_PER_SEC: u64 = 60;
+    tunable rate_limit_position_updates_burst / RATE_LIMIT_POSITION_UPDATES_BURST: u64 = 120;
+    tunable rate_limit_projectiles_per_sec / RATE_LIMIT_PROJECTILES_PER_SEC: u64 = 20;
+    tunable rate_limit_projectiles_burst / RATE_LIMIT_PROJECTILES_BURST: u64 = 40;
+    tunable rate_limit_invalid_per_sec / RATE_LIMIT_INVALID_PER_SEC: u64 = 1;
+    tunable rate_limit_invalid_burst / RATE_LIMIT_INVALID_BURST: u64 = 5;
+    /// escalation: the client is warned upon this many recent violations
+    tunable rate_limit_warn_after_violations / RATE_LIMIT_WARN_AFTER_VIOLATIONS: u64 = 20;
+    /// ... and disconnected upon this many
+    tunable rate_limit_disconnect_after_violations / RATE_LIMIT_DISCONNECT_AFTER_VIOLATIONS: u64 = 200;
+    /// incoming messages larger than this are rejected, and the connection closed
+    setting max_message_bytes / MAX_MESSAGE_BYTES: usize = 16 * 1024;
+    /// websockets event loop limits (see `ws::Settings`): all connections, including spectators
+    setting ws_max_connections / WS_MAX_CONNECTIONS: usize = 100;
+    /// messages queued per connection
+    setting ws_queue_size / WS_QUEUE_SIZE: usize = 5;
+    /// clients this far behind in reading game state snapshots only get the newest one once they catch up
+    tunable slow_client_coalesce_after_ms / SLOW_CLIENT_COALESCE_AFTER_MS: u64 = 1_000;
+    /// clients this far behind are disconnected
+    tunable slow_client_disconnect_after_ms / SLOW_CLIENT_DISCONNECT_AFTER_MS: u64 = 10_000;
+    /// messages (other than connects/disconnects and position updates) which may await the game controller
+    setting controller_inbox_capacity / CONTROLLER_INBOX_CAPACITY: usize = 1_024;
+    /// times the game controller is restarted (from its last good state) after panicking, before giving up
+    setting controller_max_restarts / CONTROLLER_MAX_RESTARTS: u32 = 10;
+    /// admin commands are appended here (as json lines), besides being logged
+    setting admin_audit_log_path / ADMIN_AUDIT_LOG_PATH: Option<String> = None;
+    /// an admin can't spawn more enemies than this at once
+    setting admin_max_spawn_enemies / ADMIN_MAX_SPAWN_ENEMIES: u32 = 1_000;
+    /// connection quality is computed over this many of the latest ping/pongs
+    setting latency_window_samples / LATENCY_WINDOW_SAMPLES: usize = 16;
+    /// how far in the past a client may claim to have fired a projectile (lag compensation cap)
+    tunable max_lag_compensation_ms / MAX_LAG_COMPENSATION_MS: u64 = 250;
+    /// when set, the game controller records every tick here, for replaying (see `recording`)
+    setting recording_path / RECORDING_PATH: Option<String> = None;
+    /// recordings include the full game state every this many ticks (0 for never), to diff replays against
+    setting recording_snapshot_interval_ticks / RECORDING_SNAPSHOT_INTERVAL_TICKS: u64 = 200;
+    /// /healthz fails if the game controller hasn't completed a tick for this long
+    setting health_max_tick_age_ms / HEALTH_MAX_TICK_AGE_MS: u64 = 1_000;
 }
 
-macro_rules! init_env_var {
-    ($name:ident) => {{
-        match env::var(stringify!($name)) {
-            Ok(value) => {
-                $name::$name = value.parse().unwrap();
-                info!("{}=[{:?}]", stringify!($name), &$name::$name);
+/// generates `set_tunables` and `tunables` over the given tunables
+macro_rules! tunables {
+    ($($name:ident),* $(,)?) => {
+        fn tunable(name: &str) -> Option<&'static AtomicU64> {
+            match name {
+                $(stringify!($name) => Some(&$name::$name),)*
+                _ => None,
+            }
+        }
+
+        /// Changes tunables while running, all together or (if that would leave the
+        /// config invalid, e.g. a burst below its rate) not at all.
+        pub fn set_tunables(changes: &[(&str, u64)]) -> Result<(), String> {
+            let mut candidate = CURRENT.read().unwrap().clone();
+            for (name, value) in tunables().into_iter().chain(changes.iter().copied()) {
+                if tunable(name).is_none() {
+                    let names: Vec<&str> = tunables().iter().map(|(name, _)| *name).collect();
+                    return Err(format!("no tunable named [{}], try one of {:?}", name, names));
+                }
+                // (tunables are all u64s, as are their fields)
+                candidate.set(name, &value.to_string());
             }
-            Err(_) => {
-                warn!("{} using default [{:?}]", stringify!($name), &$name::$name);
+            let problems = candidate.problems();
+            if !problems.is_empty() {
+                return Err(problems.join("; "));
             }
+            for (name, value) in changes {
+                tunable(name).unwrap().store(*value, Ordering::Relaxed);
+                info!("{} changed to [{}]", name, value);
+            }
+            Ok(())
+        }
+
+        /// every tunable, with its current value
+        pub fn tunables() -> Vec<(&'static str, u64)> {
+            vec![$((stringify!($name), $name()),)*]
         }
-    }};
+    };
 }
 
-define_env_var!(
-    AWAIT_CLIENT_MSG_TIMEOUT_MS,
-    Duration,
-    Duration::from_millis(50)
+tunables!(
+    RATE_LIMIT_POSITION_UPDATES_PER_SEC,
+    RATE_LIMIT_POSITION_UPDATES_BURST,
+    RATE_LIMIT_PROJECTILES_PER_SEC,
+    RATE_LIMIT_PROJECTILES_BURST,
+    RATE_LIMIT_INVALID_PER_SEC,
+    RATE_LIMIT_INVALID_BURST,
+    RATE_LIMIT_WARN_AFTER_VIOLATIONS,
+    RATE_LIMIT_DISCONNECT_AFTER_VIOLATIONS,
+    SLOW_CLIENT_COALESCE_AFTER_MS,
+    SLOW_CLIENT_DISCONNECT_AFTER_MS,
+    MAX_LAG_COMPENSATION_MS,
 );
-define_env_var!(WEBSOCKETS_PINGPONG_INTERVAL_MS, u64, 4_500);
-
-pub fn init_env_vars() {
-    unsafe {
-        init_env_var_impl!(AWAIT_CLIENT_MSG_TIMEOUT_MS, |s: String| {
-            Duration::from_millis(s.parse().unwrap())
-        });
-        init_env_var!(WEBSOCKETS_PINGPONG_INTERVAL_MS);
+
+lazy_static! {
+    /// (the defaults, until a config is installed)
+    static ref CURRENT: RwLock<ServerConfig> = RwLock::new(ServerConfig::default());
+}
+
+/// Makes `config` the one every accessor reads (and resets the tunables to its values).
+pub fn install(config: ServerConfig) {
+    config.install_tunables();
+    *CURRENT.write().unwrap() = config;
+}
+
+/// the environment variable naming a config file, when there's no `--config`
+pub const CONFIG_PATH_ENV_VAR: &str = "CONFIG_PATH";
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    File { path: String, details: String },
+    /// everything wrong with it (e.g. unparseable values, unknown flags, failed validation)
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::File { path, details } => {
+                write!(f, "cannot load config file [{}]: {}", path, details)
+            }
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// what the command line asks for
+#[derive(Debug)]
+pub enum Command {
+    Run(Box<ServerConfig>),
+    Help,
+}
+
+pub fn help() -> String {
+    let mut help = "usage: tide [<listen address>] [--config <file.toml>] [--<setting> <value>]...\n\n\
+        every setting may be given in the config file (as `name = value`), in the\n\
+        environment (as NAME=value) or as a flag (as --name value), in increasing precedence:\n"
+        .to_owned();
+    let defaults = ServerConfig::default().summary();
+    for ((field, _, doc), (name, default)) in SETTINGS.iter().zip(defaults) {
+        help += &format!(
+            "\n  {} / {} / --{}  (default {})\n",
+            field,
+            name,
+            field.replace('_', "-"),
+            default
+        );
+        for line in doc.lines() {
+            help += &format!("      {}\n", line.trim());
+        }
+    }
+    help
+}
+
+/// defaults, then the file, then the environment, then `args` (without the program name), validated
+pub fn load(args: &[String]) -> Result<Command, ConfigError> {
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        return Ok(Command::Help);
+    }
+    let Args {
+        file,
+        flags,
+        mut problems,
+    } = parse_args(args);
+    let mut config = match file.or_else(|| env::var(CONFIG_PATH_ENV_VAR).ok()) {
+        Some(path) => from_file(&path)?,
+        None => ServerConfig::default(),
+    };
+    problems.extend(override_from_env(&mut config));
+    for (name, value) in flags {
+        if let Some(Err(error)) = config.set(&name, &value) {
+            problems.push(format!("{} (command line): {}", flag_for(&name), error));
+        }
+    }
+    problems.extend(config.problems());
+    if problems.is_empty() {
+        Ok(Command::Run(Box::new(config)))
+    } else {
+        Err(ConfigError::Invalid(problems))
+    }
+}
+
+/// defaults, then the environment (for tools which don't take the server's flags), validated
+pub fn from_env() -> Result<ServerConfig, ConfigError> {
+    let mut config = ServerConfig::default();
+    let mut problems = override_from_env(&mut config);
+    problems.extend(config.problems());
+    if problems.is_empty() {
+        Ok(config)
+    } else {
+        Err(ConfigError::Invalid(problems))
+    }
+}
+
+fn from_file(path: &str) -> Result<ServerConfig, ConfigError> {
+    let file_error = |details: String| ConfigError::File {
+        path: path.to_owned(),
+        details,
+    };
+    let text = std::fs::read_to_string(path).map_err(|error| file_error(error.to_string()))?;
+    toml::from_str(&text).map_err(|error| file_error(error.to_string()))
+}
+
+fn override_from_env(config: &mut ServerConfig) -> Vec<String> {
+    let mut problems = vec![];
+    for (_, name, _) in SETTINGS {
+        if let Ok(value) = env::var(name) {
+            if let Some(Err(error)) = config.set(name, &value) {
+                problems.push(format!("{} (environment): {}", name, error));
+            }
+        }
+    }
+    problems
+}
+
+fn flag_for(name: &str) -> String {
+    format!("--{}", name.to_lowercase().replace('_', "-"))
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    file: Option<String>,
+    /// settings' names (as their accessors), with their values
+    flags: Vec<(String, String)>,
+    problems: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Args {
+    let mut parsed = Args::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            // (as before flags, the address may be given on its own)
+            None if parsed.flags.is_empty() && parsed.file.is_none() => {
+                parsed.flags.push(("LISTEN_ADDRESS".to_owned(), arg.clone()));
+                continue;
+            }
+            None => {
+                parsed.problems.push(format!("unexpected argument [{}]", arg));
+                continue;
+            }
+        };
+        let (flag, inline_value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_owned())),
+            None => (flag, None),
+        };
+        let value = match inline_value.or_else(|| args.next().cloned()) {
+            Some(value) => value,
+            None => {
+                parsed.problems.push(format!("--{} needs a value", flag));
+                continue;
+            }
+        };
+        let name = flag.to_uppercase().replace('-', "_");
+        if flag == "config" {
+            parsed.file = Some(value);
+        } else if SETTINGS.iter().any(|(_, setting, _)| *setting == name) {
+            parsed.flags.push((name, value));
+        } else {
+            parsed
+                .problems
+                .push(format!("unknown flag [--{}] (see --help)", flag));
+        }
+    }
+    parsed
+}
+
+impl ServerConfig {
+    /// what's wrong with it, if anything
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut require = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_owned());
+            }
+        };
+        require(
+            self.listen_address.parse::<SocketAddr>().is_ok(),
+            "listen_address must be an ip address and port, e.g. 0.0.0.0:3012",
+        );
+        require(
+            (1..=1_000).contains(&self.await_client_msg_timeout_ms),
+            "await_client_msg_timeout_ms (the tick interval) must be between 1 and 1000",
+        );
+        require(self.map_max_dimension > 0, "map_max_dimension must be positive");
+        require(self.max_players > 0, "max_players must be positive");
+        require(
+            self.max_players <= self.ws_max_connections,
+            "max_players can't be more than ws_max_connections",
+        );
+        require(
+            self.websockets_pingpong_interval_ms >= 100,
+            "websockets_pingpong_interval_ms must be at least 100",
+        );
+        require(
+            self.heartbeat_missed_intervals > 0,
+            "heartbeat_missed_intervals must be positive",
+        );
+        require(
+            self.auth_secret.as_deref() != Some(""),
+            "auth_secret must not be empty (leave it unset to not authenticate)",
+        );
+        for (per_sec, burst, name) in &[
+            (
+                self.rate_limit_position_updates_per_sec,
+                self.rate_limit_position_updates_burst,
+                "rate_limit_position_updates",
+            ),
+            (
+                self.rate_limit_projectiles_per_sec,
+                self.rate_limit_projectiles_burst,
+                "rate_limit_projectiles",
+            ),
+            (
+                self.rate_limit_invalid_per_sec,
+                self.rate_limit_invalid_burst,
+                "rate_limit_invalid",
+            ),
+        ] {
+            require(
+                burst >= per_sec,
+                &format!("{}_burst can't be less than {}_per_sec", name, name),
+            );
+        }
+        require(
+            self.rate_limit_warn_after_violations <= self.rate_limit_disconnect_after_violations,
+            "rate_limit_warn_after_violations can't be more than rate_limit_disconnect_after_violations",
+        );
+        require(
+            self.max_message_bytes >= 1_024,
+            "max_message_bytes must be at least 1024",
+        );
+        require(self.ws_max_connections > 0, "ws_max_connections must be positive");
+        require(self.ws_queue_size > 0, "ws_queue_size must be positive");
+        require(
+            self.slow_client_coalesce_after_ms < self.slow_client_disconnect_after_ms,
+            "slow_client_coalesce_after_ms must be less than slow_client_disconnect_after_ms",
+        );
+        require(
+            self.controller_inbox_capacity > 0,
+            "controller_inbox_capacity must be positive",
+        );
+        require(
+            self.latency_window_samples >= 2,
+            "latency_window_samples must be at least 2",
+        );
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        load, parse_args, set_tunables, Command, ConfigError, ServerConfig,
+        RATE_LIMIT_PROJECTILES_BURST,
+    };
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_owned()).collect()
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = std::env::temp_dir().join(format!("tide-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "max_players = 10\nws_max_connections = 20\n").unwrap();
+        let path = path.to_str().unwrap();
+        let config = match load(&args(&["0.0.0.0:4000", "--config", path, "--max-players=15"])) {
+            Ok(Command::Run(config)) => config,
+            other => panic!("expected a config, got {:?}", other),
+        };
+        assert_eq!(config.listen_address, "0.0.0.0:4000");
+        assert_eq!(config.max_players, 15);
+        assert_eq!(config.ws_max_connections, 20);
+        assert_eq!(config.map_max_dimension, ServerConfig::default().map_max_dimension);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let error = load(&args(&["--max-players", "lots", "--tick", "5"])).unwrap_err();
+        assert_eq!(
+            error,
+            ConfigError::Invalid(vec![
+                "unknown flag [--tick] (see --help)".to_owned(),
+                "--max-players (command line): expected a whole number, got [lots]".to_owned(),
+            ])
+        );
+
+        let config = ServerConfig {
+            max_players: 500,
+            slow_client_coalesce_after_ms: 20_000,
+            ..ServerConfig::default()
+        };
+        assert_eq!(config.problems().len(), 2);
+        assert!(ServerConfig::default().problems().is_empty());
+        assert!(matches!(load(&args(&["-h"])), Ok(Command::Help)));
+        assert_eq!(
+            parse_args(&args(&["--config"])).problems,
+            vec!["--config needs a value"]
+        );
+    }
+
+    #[test]
+    fn unknown_file_settings_are_rejected() {
+        let error = toml::from_str::<ServerConfig>("max_player = 10").unwrap_err();
+        assert!(error.to_string().contains("max_player"));
+    }
+
+    #[test]
+    fn tunables_only_change_into_a_valid_config() {
+        let burst = RATE_LIMIT_PROJECTILES_BURST();
+        let error = set_tunables(&[("RATE_LIMIT_PROJECTILES_BURST", 0)]).unwrap_err();
+        assert!(error.contains("rate_limit_projectiles_burst"), "{}", error);
+        assert_eq!(RATE_LIMIT_PROJECTILES_BURST(), burst);
+        assert!(set_tunables(&[("RATE_LIMIT_PROJECTILE_BURST", burst)]).is_err());
     }
 }
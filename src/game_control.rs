@@ -1,60 +1,326 @@
 use crate::api_types;
+use crate::clock::{Clock, ManualClock};
 use crate::config;
+use crate::gameplay::{self, SharedCatalog};
 use crate::geography::GameMap;
-use crate::intercomm::ChannelUpdate;
+use crate::intercomm::{self, ChannelUpdate, InboxReceiver, InboxSender};
+use crate::latency;
+use crate::metrics::Metrics;
+use crate::recording::{self, Recorder, TickRecord};
+use crate::sessions::SessionRegistry;
 use crate::utils;
-use api::projectile_info::lookup_projectile_info;
+use crate::utils::SerialIdGenerator;
 use api::Vec2;
 use api_types as api;
 
 use ncollide2d as nc;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-use std::collections::HashMap;
-use std::sync::mpsc;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::panic;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
 
+pub const PLAYER_STARTING_HEALTH: api::Health = 100;
+pub const ENEMY_STARTING_HEALTH: api::Health = 100;
+/// for enemies whose type is no longer in the catalog
+const FALLBACK_HITBOX: api::Hitbox = api::Hitbox::Cuboid {
+    half_width: 0.5,
+    half_height: 0.5,
+};
+
+/// `update_channel_tx` is only used to wake the game controller upon cancellation
 pub fn start_game_controller_thread(
     mut game: GameController,
-) -> Result<impl FnOnce() -> (), Box<dyn std::error::Error>> {
-    let (cancelled, cancel) = utils::make_atomic_canceller();
-    let game_controller = thread::Builder::new()
-        .name("GameController".to_owned())
-        .spawn(move || game.loop_until_cancelled(cancelled))?;
-    let terminate_game_controller = move || {
+    cancellation: utils::CancellationToken,
+    update_channel_tx: InboxSender,
+) -> Result<GameControllerThread, Box<dyn std::error::Error>> {
+    cancellation.on_cancel(move || {
         info!("requesting game controller thread to stop...");
-        cancel();
-        match game_controller.join().unwrap() {
+        update_channel_tx.wake();
+    });
+    let metrics = game.metrics.clone();
+    metrics.controller_up.store(1, Ordering::Relaxed);
+    let handle = thread::Builder::new()
+        .name("GameController".to_owned())
+        .spawn(move || {
+            let result = supervise(&mut game, &cancellation);
+            metrics.controller_up.store(0, Ordering::Relaxed);
+            result
+        })?;
+    Ok(GameControllerThread { handle })
+}
+
+/// Runs the game controller, restarting it from its last good state if it panics
+/// (up to `CONTROLLER_MAX_RESTARTS` times).
+fn supervise(
+    game: &mut GameController,
+    cancellation: &utils::CancellationToken,
+) -> Result<(), String> {
+    let mut restarts = 0;
+    loop {
+        let run = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            game.loop_until_cancelled(cancellation)
+        }));
+        let details = match run {
+            Ok(result) => return result,
+            Err(panic) => panic_details(&*panic),
+        };
+        if restarts >= config::CONTROLLER_MAX_RESTARTS() {
+            return Err(format!(
+                "game controller panicked [{}], out of restarts",
+                details
+            ));
+        }
+        restarts += 1;
+        game.metrics
+            .controller_restarts
+            .fetch_add(1, Ordering::Relaxed);
+        error!(
+            "game controller panicked [{}], restarting from last good state (restart {}/{})",
+            details,
+            restarts,
+            config::CONTROLLER_MAX_RESTARTS()
+        );
+        game.restore_checkpoint();
+    }
+}
+
+fn panic_details(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+pub struct GameControllerThread {
+    handle: thread::JoinHandle<Result<(), String>>,
+}
+
+impl GameControllerThread {
+    /// waits for the game controller thread, giving back its result
+    pub fn join(self) -> Result<(), String> {
+        let result = match self.handle.join() {
+            Ok(result) => result,
+            Err(_) => Err("game controller thread panicked".to_owned()),
+        };
+        match &result {
             Err(details) => error!("game controller failed, details: [{}]", details),
             Ok(_) => info!("game controller thread closed without error."),
         };
-    };
-    Ok(terminate_game_controller)
+        result
+    }
 }
 
 #[derive(Debug)]
 pub struct GameController {
-    update_channel_rx: mpsc::Receiver<ChannelUpdate>,
+    update_channel_rx: InboxReceiver,
     broadcaster: ws::Sender,
     state: api::GameState,
+    /// the state as of the end of the last completed tick
+    checkpoint: api::GameState,
+    /// whether the state may have changed since the checkpoint (if not, it isn't taken again)
+    changed_since_checkpoint: bool,
+    /// the control messages of the tick in progress, which restoring the checkpoint mustn't lose
+    tick_controls: Vec<ChannelUpdate>,
+    /// (the index in `tick_controls` of the one being handled, the culprit should it panic)
+    handling_control: Option<usize>,
+    /// handled first thing next tick (see `restore_checkpoint`)
+    carried_over: Vec<ChannelUpdate>,
     map: GameMap,
+    sessions: Arc<SessionRegistry>,
+    enemy_id_gen: utils::EnemyIdGenerator,
+    metrics: Arc<Metrics>,
+    clock: Arc<dyn Clock>,
+    /// (recorded, so that replays spawn the same enemies in the same places)
+    seed: u64,
+    rng: StdRng,
+    /// ticks completed
+    tick: u64,
+    /// the clock reading the current tick is simulated at
+    now_ms: u64,
+    recorder: Option<Recorder>,
+    /// projectile and enemy stats, and spawn rates (see `gameplay`)
+    catalog: api::Catalog,
+    /// reloaded catalogs, switched to between ticks
+    catalog_reloads: Option<mpsc::Receiver<api::Catalog>>,
+    /// what clients are sent upon connecting
+    shared_catalog: Option<Arc<SharedCatalog>>,
+    /// when each player last fired each projectile type (see `ProjectileInfo::fire_rate`)
+    last_fired_ms: HashMap<(api::PlayerId, api::ProjectileType), u64>,
+    /// the clock reading of the first tick (waves are timed from it)
+    match_started_ms: Option<u64>,
+    /// waves timed before this (since the match started) have been spawned
+    waves_due_from_ms: u64,
+    /// (since the match started)
+    last_scheduled_spawn_ms: u64,
+    /// when each enemy last attacked (see `EnemyInfo::damage`)
+    last_attack_ms: HashMap<isize, u64>,
 }
 
 impl GameController {
     pub fn new(
-        update_channel_rx: mpsc::Receiver<ChannelUpdate>,
+        update_channel_rx: InboxReceiver,
         broadcaster: ws::Sender,
         map: GameMap,
+        sessions: Arc<SessionRegistry>,
+        metrics: Arc<Metrics>,
+        clock: Arc<dyn Clock>,
     ) -> GameController {
+        let seed = rand::random();
+        let now_ms = clock.now_ms();
         GameController {
             update_channel_rx,
-            broadcaster: broadcaster,
-            map: map,
+            broadcaster,
+            map,
+            sessions,
+            enemy_id_gen: utils::EnemyIdGenerator::new(1),
+            metrics,
+            clock,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            tick: 0,
+            now_ms,
+            recorder: None,
+            catalog: gameplay::built_in(),
+            catalog_reloads: None,
+            shared_catalog: None,
+            last_fired_ms: HashMap::new(),
+            match_started_ms: None,
+            waves_due_from_ms: 0,
+            last_scheduled_spawn_ms: 0,
+            last_attack_ms: HashMap::new(),
             state: api::GameState {
                 players: HashMap::new(),
                 enemies: vec![],
                 projectiles: vec![],
+                paused: false,
             },
+            checkpoint: api::GameState {
+                players: HashMap::new(),
+                enemies: vec![],
+                projectiles: vec![],
+                paused: false,
+            },
+            changed_since_checkpoint: false,
+            tick_controls: vec![],
+            handling_control: None,
+            carried_over: vec![],
+        }
+    }
+
+    /// A game controller connected to nothing, for replaying a recording into
+    /// (see `recording::replay`).
+    pub fn replaying(seed: u64, map_max_dimension: usize) -> GameController {
+        let (_, update_channel_rx) = intercomm::inbox(1);
+        // (never listens, snapshots go nowhere)
+        #[allow(clippy::result_large_err)]
+        let broadcaster = ws::WebSocket::new(|_| |_| Ok(())).unwrap().broadcaster();
+        let mut game = GameController::new(
+            update_channel_rx,
+            broadcaster,
+            GameMap {
+                max_dimension: map_max_dimension,
+            },
+            Arc::new(SessionRegistry::new(Duration::from_millis(0))),
+            Arc::new(Metrics::default()),
+            // (the recorded clock readings are passed to each tick)
+            Arc::new(ManualClock::new(Duration::from_millis(0))),
+        );
+        game.seed = seed;
+        game.rng = StdRng::seed_from_u64(seed);
+        game
+    }
+
+    pub fn state(&self) -> &api::GameState {
+        &self.state
+    }
+
+    /// what a recording of this game needs to start from
+    pub fn recording_header(&self) -> recording::Header {
+        recording::Header {
+            version: recording::VERSION,
+            seed: self.seed,
+            map_max_dimension: self.map.max_dimension,
+            tunables: config::tunables()
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value))
+                .collect(),
+            catalog: Some(self.catalog.clone()),
+        }
+    }
+
+    pub fn catalog(&self) -> &api::Catalog {
+        &self.catalog
+    }
+
+    /// (only projectiles fired from now on are affected)
+    pub fn set_catalog(&mut self, catalog: api::Catalog) {
+        if let Some(shared) = &self.shared_catalog {
+            shared.set(&catalog);
+        }
+        self.catalog = catalog;
+        // (e.g. teams may change)
+        self.changed_since_checkpoint = true;
+    }
+
+    /// keeps `shared` up to date with the catalog in play, for the server to send
+    /// clients upon connecting
+    pub fn share_catalog_with(&mut self, shared: Arc<SharedCatalog>) {
+        shared.set(&self.catalog);
+        self.shared_catalog = Some(shared);
+    }
+
+    /// switches to each catalog received, between ticks (see `gameplay::watch`)
+    pub fn reload_catalog_from(&mut self, reloads: mpsc::Receiver<api::Catalog>) {
+        self.catalog_reloads = Some(reloads);
+    }
+
+    /// records every tick from now on (see `recording`)
+    pub fn record_to(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    fn record_metrics(&self, tick_duration: Duration) {
+        let metrics = &self.metrics;
+        metrics.tick_duration.observe(tick_duration);
+        let state = &self.state;
+        metrics
+            .players
+            .store(state.players.len() as u64, Ordering::Relaxed);
+        metrics
+            .enemies
+            .store(state.enemies.len() as u64, Ordering::Relaxed);
+        metrics
+            .projectiles
+            .store(state.projectiles.len() as u64, Ordering::Relaxed);
+        metrics
+            .last_tick_unix_ms
+            .store(utils::unix_time().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Throws away whatever happened since the last completed tick, except for its
+    /// control messages (connects, disconnects and admin commands), which are carried
+    /// over to the next tick; bar the one it panicked on, if any.
+    pub fn restore_checkpoint(&mut self) {
+        self.state = self.checkpoint.clone();
+        self.changed_since_checkpoint = false;
+        let mut controls = std::mem::take(&mut self.tick_controls);
+        if let Some(culprit) = self.handling_control.take() {
+            let ChannelUpdate { id, .. } = controls.remove(culprit);
+            warn!(
+                "dropping the control message from player [{}] which the game controller panicked on",
+                id
+            );
         }
+        self.carried_over = controls;
     }
 
     pub fn handle_player_update(
@@ -63,143 +329,455 @@ impl GameController {
         update: api::ClientUpdate,
     ) -> Result<(), String> {
         match update {
-            api::ClientUpdate::PlayerConnected(()) => self.try_connect_player(id)?,
-            api::ClientUpdate::PlayerDisconnected(()) => self.disconnect_player(id),
-            api::ClientUpdate::PositionUpdate(position) => self.get_player(&id).position = position,
-            api::ClientUpdate::ProjectileCreated(proj) => self.handle_projectile_created(id, proj),
+            api::ClientUpdate::PlayerConnected(identity) => {
+                self.try_connect_player(id, identity)?
+            }
+            api::ClientUpdate::PlayerDisconnected(()) => self.disconnect_player(id)?,
+            // nothing moves while paused
+            api::ClientUpdate::PositionUpdate(_) | api::ClientUpdate::ProjectileCreated(_)
+                if self.state.paused => {}
+            api::ClientUpdate::PositionUpdate(position) => {
+                // (whatever else, a non-finite position would break collision checks)
+                let max = self.map.max_dimension as f32;
+                if !position.xy.iter().all(|c| (0.0..=max).contains(c)) {
+                    return Err("position outside of the map".to_owned());
+                }
+                // (the inbox reorders, so the player may be gone already)
+                if let Some(player) = self.state.players.get_mut(&id) {
+                    player.position = position
+                }
+            }
+            api::ClientUpdate::ProjectileCreated(proj) => {
+                self.handle_projectile_created(id, proj)?
+            }
+            api::ClientUpdate::ConnectionQualityMeasured(quality) => {
+                self.get_player(&id)?.connection_quality = Some(quality)
+            }
+            api::ClientUpdate::Admin(command) => self.handle_admin_command(command)?,
         };
         debug!(" --> state: {:?}", self.state.players);
         Ok(())
     }
 
+    /// the game side of admin commands (the rest are handled by the server)
+    pub fn handle_admin_command(&mut self, command: api::AdminCommand) -> Result<(), String> {
+        match command {
+            api::AdminCommand::PauseMatch => self.state.paused = true,
+            api::AdminCommand::ResumeMatch => {
+                if self.state.paused {
+                    // projectiles pick up where they were, rather than jumping by the pause
+                    for player_proj in self.state.projectiles.iter_mut() {
+                        player_proj.projectile.origin.time_ms = self.now_ms;
+                    }
+                    // (and so do enemies)
+                    for enemy in self.state.enemies.iter_mut() {
+                        enemy.position.time_ms = self.now_ms;
+                    }
+                }
+                self.state.paused = false;
+            }
+            api::AdminCommand::SpawnEnemies { count, enemy_type } => {
+                self.spawn_enemies(count, enemy_type.as_deref())?
+            }
+            other => return Err(format!("not a game command: [{:?}]", other)),
+        }
+        Ok(())
+    }
+
+    /// at random positions on the map, of the catalog's first type unless given one
+    pub fn spawn_enemies(&mut self, count: u32, enemy_type: Option<&str>) -> Result<(), String> {
+        let (enemy_type, info) = match enemy_type {
+            Some(enemy_type) => self
+                .catalog
+                .enemies
+                .get_key_value(enemy_type)
+                .ok_or_else(|| format!("no such enemy [{}]", enemy_type))?,
+            None => self
+                .catalog
+                .enemies
+                .iter()
+                .next()
+                .ok_or_else(|| "there are no enemies in the catalog".to_owned())?,
+        };
+        let max = self.map.max_dimension as f32;
+        let now_ms = self.now_ms;
+        let rng = &mut self.rng;
+        for _ in 0..count {
+            self.state.enemies.push(api::Enemy {
+                enemy_id: self.enemy_id_gen.get_next_id() as isize,
+                enemy_type: enemy_type.clone(),
+                position: api::PositionStamped {
+                    xy: api::Vec2::new(rng.gen_range(0.0, max), rng.gen_range(0.0, max)),
+                    time_ms: now_ms,
+                },
+                health: info.health,
+                status: api::EnemyStatus::Alive,
+            });
+        }
+        Ok(())
+    }
+
+    /// the catalog's waves which came due, and its steady trickle (up to `max_alive`)
+    fn spawn_scheduled_enemies(&mut self) {
+        let now_ms = self.now_ms;
+        let since_start_ms = now_ms.saturating_sub(self.match_started_ms.unwrap_or(now_ms));
+        let due_from_ms = self.waves_due_from_ms;
+        let due_waves: Vec<api::Wave> = self
+            .catalog
+            .waves
+            .iter()
+            .filter(|wave| (due_from_ms..=since_start_ms).contains(&wave.after_ms))
+            .cloned()
+            .collect();
+        self.waves_due_from_ms = since_start_ms + 1;
+        for wave in due_waves {
+            self.spawn_scheduled(wave.count, wave.enemy_type.as_deref());
+        }
+
+        let spawning = &self.catalog.spawning;
+        if spawning.interval_ms == 0
+            || since_start_ms < self.last_scheduled_spawn_ms + spawning.interval_ms
+        {
+            return;
+        }
+        self.last_scheduled_spawn_ms = since_start_ms;
+        let alive = self
+            .state
+            .enemies
+            .iter()
+            .filter(|enemy| enemy.status == api::EnemyStatus::Alive)
+            .count() as u32;
+        let count = spawning.count.min(spawning.max_alive.saturating_sub(alive));
+        let enemy_type = spawning.enemy_type.clone();
+        self.spawn_scheduled(count, enemy_type.as_deref());
+    }
+
+    /// (the catalog was validated, but a bad one only costs its spawns)
+    fn spawn_scheduled(&mut self, count: u32, enemy_type: Option<&str>) {
+        if let Err(details) = self.spawn_enemies(count, enemy_type) {
+            warn!("cannot spawn scheduled enemies: [{}]", details);
+        }
+    }
+
+    /// Chasers head for the nearest connected player, and whoever is within an
+    /// enemy's attack range takes its damage, once a second.
+    fn move_enemies(&mut self) {
+        let now_ms = self.now_ms;
+        let players = &mut self.state.players;
+        let last_attack_ms = &mut self.last_attack_ms;
+        for enemy in self.state.enemies.iter_mut() {
+            if enemy.status != api::EnemyStatus::Alive {
+                last_attack_ms.remove(&enemy.enemy_id);
+                continue;
+            }
+            let info = match self.catalog.enemies.get(&enemy.enemy_type) {
+                Some(info) => info,
+                // (its type was reloaded away, it stays put)
+                None => continue,
+            };
+            let delta_secs = now_ms.saturating_sub(enemy.position.time_ms) as f32 / 1000.0;
+            enemy.position.time_ms = now_ms;
+            let target = match nearest_connected_player(players, enemy.position.xy) {
+                Some(target) => target,
+                None => continue,
+            };
+            let to_target = players[&target].position.xy - enemy.position.xy;
+            let distance = to_target.norm();
+            if info.behavior == api::Behavior::Chase && distance > info.attack_range {
+                let step = (info.speed * delta_secs).min(distance - info.attack_range);
+                enemy.position.xy += to_target * (step / distance);
+            }
+            let in_range =
+                (players[&target].position.xy - enemy.position.xy).norm() <= info.attack_range;
+            let rested = match last_attack_ms.get(&enemy.enemy_id) {
+                Some(&attacked_ms) => now_ms.saturating_sub(attacked_ms) >= 1000,
+                None => true,
+            };
+            if info.damage > 0 && in_range && rested {
+                last_attack_ms.insert(enemy.enemy_id, now_ms);
+                let player = players.get_mut(&target).unwrap();
+                player.health = (player.health - info.damage).max(0);
+            }
+        }
+    }
+
     pub fn progress_projectiles(&mut self) -> Result<(), String> {
-        let now_ms = utils::unix_time().as_millis() as u64;
-        let mut remaining_alive_enemies: Vec<&mut api::Enemy> =
-            self.state.enemies.iter_mut().collect();
+        let now_ms = self.now_ms;
+        let catalog = &self.catalog;
+        let players = &mut self.state.players;
+        let enemies = &mut self.state.enemies;
         for player_proj in self.state.projectiles.iter_mut() {
-            let delta_ms = now_ms - player_proj.projectile.origin.time_ms;
+            let delta_ms = now_ms.saturating_sub(player_proj.projectile.origin.time_ms);
             let delta_secs = delta_ms as f32 / 1000.0;
             let pos_update_vector = player_proj.projectile.vel * delta_secs;
-            remaining_alive_enemies = remaining_alive_enemies
-                .into_iter()
-                .filter_map(|enemy: &mut api::Enemy| {
-                    if *player_proj.current_info.num_penetrations.as_ref().unwrap() > 0 {
-                        // Check if position update vector at all intersects the enemy bounding box
-                        let projectile_hits_enemy =
-                            projectile_ray_scans_enemy(&player_proj.projectile, &enemy);
-                        if projectile_hits_enemy {
-                            // TODO: add way to optionally backoff/decrease {speed,damage} on the projectile
-                            enemy.health -= player_proj.current_info.damage;
-                            if enemy.health < 0 {
-                                enemy.status = api::EnemyStatus::Dead;
-                            }
-                            *player_proj.current_info.num_penetrations.as_mut().unwrap() -= 1;
+            // (only as far as it moved this tick)
+            let reach = remaining_range(player_proj).min(pos_update_vector.norm());
+            let shooter = player_proj.player_id;
+            let in_projectile_path: Vec<_> = targets_in_path(
+                enemies,
+                catalog,
+                players,
+                shooter,
+                &player_proj.projectile,
+                reach,
+            )
+            .into_iter()
+            // (e.g. one it's still inside of since last tick)
+            .filter(|(_, target)| match target {
+                Target::Enemy(enemy) => !player_proj.enemies_hit.contains(&enemy.enemy_id),
+                Target::Player(victim) => !player_proj.players_hit.contains(victim),
+            })
+            .collect();
+            // (None is unlimited, though still nothing is hit twice)
+            let penetrations = match player_proj.current_info.num_penetrations {
+                Some(penetrations) => penetrations.max(0) as usize,
+                None => usize::MAX,
+            };
+            let damage = player_proj.current_info.damage;
+            for (_, target) in in_projectile_path.into_iter().take(penetrations) {
+                match target {
+                    Target::Enemy(enemy) => {
+                        player_proj.enemies_hit.insert(enemy.enemy_id);
+                        // TODO: add way to optionally backoff/decrease {speed,damage} on the projectile
+                        enemy.health -= damage;
+                        if enemy.health < 0 {
+                            enemy.status = api::EnemyStatus::Dead;
+                        }
+                        if let Some(shooter) = players.get_mut(&shooter) {
+                            record_hit(
+                                &mut shooter.stats,
+                                damage,
+                                enemy.status == api::EnemyStatus::Dead,
+                                catalog
+                                    .enemies
+                                    .get(&enemy.enemy_type)
+                                    .map_or(0, |info| info.score_value),
+                            );
                         }
                     }
-                    return match enemy.status {
-                        api::EnemyStatus::Alive => Some(enemy),
-                        api::EnemyStatus::Dead => None,
-                        api::EnemyStatus::Unspecified => panic!("invalid enemy state"),
-                    };
-                })
-                .collect();
+                    Target::Player(victim) => {
+                        player_proj.players_hit.insert(victim);
+                        hit_player(players, shooter, victim, damage)
+                    }
+                }
+                if let Some(num_penetrations) = player_proj.current_info.num_penetrations.as_mut() {
+                    *num_penetrations -= 1;
+                }
+            }
             // move the projectile forward
             player_proj.projectile.origin = api::PositionStamped {
                 xy: player_proj.projectile.origin.xy + pos_update_vector,
                 time_ms: now_ms,
             };
+            player_proj.travelled += pos_update_vector.norm();
         }
+        // (out of range, out of penetrations, or off the map)
+        let max = self.map.max_dimension as f32;
+        self.state.projectiles.retain(|player_proj| {
+            let xy = player_proj.projectile.origin.xy;
+            remaining_range(player_proj) > 0.0
+                && player_proj.current_info.num_penetrations.unwrap_or(1) > 0
+                && xy.iter().all(|c| (0.0..=max).contains(c))
+        });
         Ok(())
     }
 
     pub fn broadcast_state(&mut self) -> Result<(), String> {
         let game_state_json = serde_json::ser::to_string(&self.state).unwrap();
-        if let Err(e) = self.broadcaster.send(game_state_json) {
-            Err(e.details.to_string())
-        } else {
-            Ok(())
+        match self.broadcaster.send(game_state_json) {
+            // the event loop is swamped; this snapshot is stale by the next one anyway
+            Err(ws::Error {
+                kind: ws::ErrorKind::Queue(_),
+                ..
+            }) => {
+                warn!("websockets event loop queue full, dropped game state snapshot");
+                Ok(())
+            }
+            Err(e) => Err(e.details.to_string()),
+            Ok(()) => Ok(()),
         }
     }
 
-    pub fn loop_until_cancelled<F: Fn() -> bool>(&mut self, cancelled: F) -> Result<(), String> {
+    /// tells every client about a new catalog
+    fn broadcast_catalog(&mut self) {
+        let catalog_msg = gameplay::catalog_message(&self.catalog);
+        // (the game goes on by the new catalog regardless)
+        if let Err(e) = self.broadcaster.send(catalog_msg) {
+            warn!("failed to send the reloaded catalog to clients: [{:?}]", e);
+        }
+    }
+
+    pub fn loop_until_cancelled(
+        &mut self,
+        cancellation: &utils::CancellationToken,
+    ) -> Result<(), String> {
         info!("game controller started.");
 
         // TODO: can we give branch prediction compiler hint here? (in rust)
-        while !cancelled() {
-            self.progress_projectiles()?;
-            self.broadcast_state()?;
-            // We'll wait as long as the full timeout for any client messages.
+        while !cancellation.is_cancelled() {
+            // We'll wait as long as the full timeout for any client messages,
+            // then handle everything which arrived meanwhile.
             // Thus, the timeout is the worst-case granularity of internal updates.
-            // If we get client message *more* frequently, we expect to see
-            // higher granularity to compensate.
-            match self
+            let batch = self
                 .update_channel_rx
-                .recv_timeout(config::AWAIT_CLIENT_MSG_TIMEOUT_MS())
-            {
-                Ok(ChannelUpdate { id, update }) => self.handle_player_update(id, update)?,
-                Err(mpsc::RecvTimeoutError::Timeout) => continue,
-                Err(mpsc::RecvTimeoutError::Disconnected) => {
-                    return Err("update channel disconnected".to_owned());
-                }
-            };
+                .recv_batch(Duration::from_millis(config::AWAIT_CLIENT_MSG_TIMEOUT_MS()));
+            let tick_started = Instant::now();
+            self.step(batch)?;
+            self.broadcast_state()?;
+            // (an idle game, e.g. an empty server, isn't copied every tick)
+            if self.changed_since_checkpoint {
+                self.checkpoint = self.state.clone();
+                self.changed_since_checkpoint = false;
+            }
+            self.tick_controls.clear();
+            self.record_metrics(tick_started.elapsed());
         }
 
         info!("game updater game_controller detected cancellation, terminating...");
 
         Ok(())
     }
-    pub fn get_player(&mut self, id: &api::PlayerId) -> &mut api::Player {
-        self.state.players.get_mut(id).unwrap()
+    pub fn get_player(&mut self, id: &api::PlayerId) -> Result<&mut api::Player, String> {
+        self.state
+            .players
+            .get_mut(id)
+            .ok_or_else(|| format!("unknown player [{}]", id))
     }
     pub fn handle_projectile_created(
         &mut self,
         id: api::PlayerId,
-        projectile: api::ProjectileSnaphot,
-    ) {
-        let projectile_info = lookup_projectile_info(projectile.projectile_type);
-        match projectile_info.speed {
-            Some(speed) => {
-                // TODO: how do we handle possibility of crossing
-                // over enemies which should have been hit (due to
-                // insufficient granularity)
-                self.state.projectiles.push(api::PlayerProjectile {
-                    player_id: id,
-                    projectile: api::ProjectileSnaphot {
-                        projectile_type: projectile.projectile_type,
-                        origin: projectile.origin,
-                        vel: projectile.vel.normalize().scale(speed),
-                    },
-                    current_info: projectile_info.clone(),
-                });
+        mut projectile: api::ProjectileSnaphot,
+    ) -> Result<(), String> {
+        let is_finite = |v: &api::Vec2| v.iter().all(|c| c.is_finite());
+        if !is_finite(&projectile.origin.xy) || !is_finite(&projectile.vel) {
+            return Err("projectile with non-finite position or velocity".to_owned());
+        }
+        if projectile.vel.norm() == 0.0 {
+            return Err("projectile without direction".to_owned());
+        }
+        let projectile_info = *self
+            .catalog
+            .projectiles
+            .get(&projectile.projectile_type)
+            .ok_or_else(|| format!("no such projectile [{}]", projectile.projectile_type))?;
+        if let Some(shooter) = self.state.players.get(&id) {
+            // don't trust the client's clock further than their connection explains
+            let now_ms = self.now_ms;
+            let allowance_ms = latency::lag_allowance_ms(
+                shooter.connection_quality.as_ref(),
+                config::MAX_LAG_COMPENSATION_MS(),
+            );
+            projectile.origin.time_ms = projectile
+                .origin
+                .time_ms
+                .max(now_ms.saturating_sub(allowance_ms))
+                .min(now_ms);
+        }
+        if let Some(fire_rate) = projectile_info.fire_rate {
+            let fired_ms = projectile.origin.time_ms;
+            let key = (id, projectile.projectile_type.clone());
+            if let Some(&last_fired_ms) = self.last_fired_ms.get(&key) {
+                if (fired_ms.saturating_sub(last_fired_ms) as f32) < 1000.0 / fire_rate {
+                    return Err(format!(
+                        "firing [{}] faster than {} per second",
+                        projectile.projectile_type, fire_rate
+                    ));
+                }
             }
-            None => {
-                let (mut in_projectile_path, _): (Vec<&mut api::Enemy>, Vec<&mut api::Enemy>) =
-                    self.state
-                        .enemies
-                        .iter_mut()
-                        .partition(|ref enemy| projectile_ray_scans_enemy(&projectile, enemy));
-                for enemy in in_projectile_path.iter_mut() {
-                    enemy.health -= projectile_info.damage;
-                    if enemy.health <= 0 {
-                        enemy.status = api::EnemyStatus::Dead;
-                    }
+            self.last_fired_ms.insert(key, fired_ms);
+        }
+        if let Some(shooter) = self.state.players.get_mut(&id) {
+            shooter.stats.shots_fired += 1;
+        }
+        for direction in pellet_directions(projectile.vel.normalize(), &projectile_info) {
+            let pellet = api::ProjectileSnaphot {
+                vel: direction,
+                ..projectile.clone()
+            };
+            match projectile_info.speed {
+                Some(speed) => {
+                    self.state.projectiles.push(api::PlayerProjectile {
+                        player_id: id,
+                        projectile: api::ProjectileSnaphot {
+                            vel: direction.scale(speed),
+                            ..pellet
+                        },
+                        current_info: projectile_info,
+                        travelled: 0.0,
+                        enemies_hit: HashSet::new(),
+                        players_hit: HashSet::new(),
+                    });
                 }
+                None => self.hit_scan(id, &pellet, &projectile_info),
             }
         }
+        Ok(())
     }
 
-    pub fn try_connect_player(&mut self, id: api::PlayerId) -> Result<(), String> {
+    /// the nearest (alive) enemies along the ray, and players (see `Pvp`), as many
+    /// as it penetrates
+    fn hit_scan(
+        &mut self,
+        id: api::PlayerId,
+        ray: &api::ProjectileSnaphot,
+        projectile_info: &api::projectile_info::ProjectileInfo,
+    ) {
+        let reach = projectile_info.range.unwrap_or(f32::MAX);
+        let enemy_types = &self.catalog.enemies;
+        let in_projectile_path = targets_in_path(
+            &mut self.state.enemies,
+            &self.catalog,
+            &self.state.players,
+            id,
+            ray,
+            reach,
+        );
+        let penetrations = match projectile_info.num_penetrations {
+            Some(penetrations) => penetrations.max(0) as usize,
+            None => in_projectile_path.len(),
+        };
+        for (_, target) in in_projectile_path.into_iter().take(penetrations) {
+            let enemy = match target {
+                Target::Enemy(enemy) => enemy,
+                Target::Player(victim) => {
+                    hit_player(&mut self.state.players, id, victim, projectile_info.damage);
+                    continue;
+                }
+            };
+            enemy.health -= projectile_info.damage;
+            if enemy.health <= 0 {
+                enemy.status = api::EnemyStatus::Dead;
+            }
+            if let Some(shooter) = self.state.players.get_mut(&id) {
+                record_hit(
+                    &mut shooter.stats,
+                    projectile_info.damage,
+                    enemy.status == api::EnemyStatus::Dead,
+                    enemy_types
+                        .get(&enemy.enemy_type)
+                        .map_or(0, |info| info.score_value),
+                );
+            }
+        }
+    }
+
+    pub fn try_connect_player(
+        &mut self,
+        id: api::PlayerId,
+        identity: Option<api::Identity>,
+    ) -> Result<(), String> {
         match self.state.players.get_mut(&id) {
             // if we had this player before, reconnect them
             Some(player) => match player.connection_status {
                 api::ConnectionStatus::Disconnected => {
-                    player.connection_status = api::ConnectionStatus::Connected
+                    player.connection_status = api::ConnectionStatus::Connected;
+                    if identity.is_some() {
+                        player.identity = identity;
+                    }
                 }
+                // (the player id resolver never hands out an id which is in use)
                 _ => {
-                    return Err(format!(
-                        "cannot have duplicate client addresses, got [{}]",
-                        &id
-                    ));
+                    return Err(format!("player [{}] is already connected", &id));
                 }
             },
             // if never seen this player, add them
@@ -212,6 +790,11 @@ impl GameController {
                             time_ms: 0,
                         },
                         connection_status: api::ConnectionStatus::Connected,
+                        health: PLAYER_STARTING_HEALTH,
+                        stats: api::PlayerStats::default(),
+                        team: None,
+                        identity,
+                        connection_quality: None,
                         authr_status: api::AuthorizationStatus::GoodStanding,
                     },
                 );
@@ -221,30 +804,919 @@ impl GameController {
         Ok(())
     }
 
-    pub fn disconnect_player(&mut self, id: api::PlayerId) {
+    /// the player stays in the game (as DISCONNECTED) until their session expires
+    pub fn disconnect_player(&mut self, id: api::PlayerId) -> Result<(), String> {
+        self.get_player(&id)?.connection_status = api::ConnectionStatus::Disconnected;
+        Ok(())
+    }
+
+    /// One tick at the clock's current reading, evicting whoever's session expired
+    /// (and recording it all, if recording). A reloaded catalog is switched to
+    /// first, so that the whole tick plays by it, and control messages carried over
+    /// from a tick which panicked go before `updates`.
+    pub fn step(&mut self, updates: Vec<ChannelUpdate>) -> Result<(), String> {
+        let mut carried_over = std::mem::take(&mut self.carried_over);
+        carried_over.extend(updates);
+        let updates = carried_over;
+        let now_ms = self.clock.now_ms();
+        let evicted = self.expired_players();
+        // (only the latest matters)
+        let reloaded = match &self.catalog_reloads {
+            Some(reloads) => reloads.try_iter().last(),
+            None => None,
+        };
+        let record = match self.recorder {
+            Some(_) => {
+                let mut record = TickRecord::new(self.tick + 1, now_ms, &updates, &evicted);
+                record.catalog = reloaded.clone();
+                Some(record)
+            }
+            None => None,
+        };
+        if let Some(catalog) = reloaded {
+            self.set_catalog(catalog);
+            self.broadcast_catalog();
+        }
+        self.apply_tick(now_ms, updates, &evicted)?;
+        if let Some(record) = record {
+            self.record(record);
+        }
+        Ok(())
+    }
+
+    /// One step of the simulation, at the clock reading `now_ms`: applies the
+    /// updates, evicts the given players, then moves everything along.
+    /// (everything a tick depends on is passed in, so that recordings replay exactly)
+    pub fn apply_tick(
+        &mut self,
+        now_ms: u64,
+        updates: Vec<ChannelUpdate>,
+        evicted: &[api::PlayerId],
+    ) -> Result<(), String> {
+        self.tick += 1;
+        self.now_ms = now_ms;
+        self.match_started_ms.get_or_insert(now_ms);
+        let idle = |state: &api::GameState| {
+            state.paused || (state.enemies.is_empty() && state.projectiles.is_empty())
+        };
+        let was_idle = updates.is_empty() && evicted.is_empty() && idle(&self.state);
+        self.tick_controls = updates
+            .iter()
+            .filter(|update| update.is_control())
+            .cloned()
+            .collect();
+        let mut controls_seen = 0;
+        for update in updates {
+            if update.is_control() {
+                self.handling_control = Some(controls_seen);
+                controls_seen += 1;
+            }
+            let ChannelUpdate { id, update } = update;
+            let handled = self.handle_player_update(id, update);
+            self.handling_control = None;
+            // a bad update only affects its sender
+            if let Err(details) = handled {
+                warn!("rejected update from player [{}]: [{}]", id, details);
+                self.metrics
+                    .updates_rejected
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        for id in evicted {
+            info!("session of player [{}] expired, removing them", id);
+            self.state.players.remove(id);
+            self.last_fired_ms.retain(|(shooter, _), _| shooter != id);
+        }
+        self.assign_teams();
+        if !self.state.paused {
+            self.spawn_scheduled_enemies();
+            self.move_enemies();
+            self.progress_projectiles()?;
+        }
+        self.changed_since_checkpoint |= !(was_idle && idle(&self.state));
+        Ok(())
+    }
+
+    /// Puts whoever has no team (e.g. newcomers) on the smallest one, the first of
+    /// them if tied; outside of team matches, nobody has a team.
+    fn assign_teams(&mut self) {
+        let pvp = &self.catalog.pvp;
+        let teams = if pvp.enabled { pvp.teams } else { 0 };
+        let players = &mut self.state.players;
+        if teams == 0 {
+            for player in players.values_mut() {
+                player.team = None;
+            }
+            return;
+        }
+        let mut sizes = vec![0; teams as usize];
+        let mut teamless = vec![];
+        for (id, player) in players.iter() {
+            match player.team.filter(|team| *team < teams) {
+                Some(team) => sizes[team as usize] += 1,
+                None => teamless.push(*id),
+            }
+        }
+        // (by id, so that replays assign the same teams)
+        teamless.sort();
+        for id in teamless {
+            let (smallest, _) = sizes
+                .iter()
+                .enumerate()
+                .min_by_key(|(team, size)| (**size, *team))
+                .unwrap();
+            sizes[smallest] += 1;
+            players.get_mut(&id).unwrap().team = Some(smallest as u32);
+        }
+    }
+
+    /// players which were disconnected for longer than the resume grace period
+    fn expired_players(&self) -> Vec<api::PlayerId> {
         if cfg!(feature = "ip-address-player-ids") {
-            self.get_player(&id).connection_status = api::ConnectionStatus::Disconnected;
-        } else {
-            self.state.players.remove(&id);
+            // players are kept around for whenever their address reconnects
+            return vec![];
+        }
+        self.state
+            .players
+            .iter()
+            .filter(|(id, player)| {
+                matches!(
+                    player.connection_status,
+                    api::ConnectionStatus::Disconnected
+                ) && self.sessions.expire(**id)
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// (a failing recorder stops recording, rather than the game)
+    fn record(&mut self, record: TickRecord) {
+        let recorder = self.recorder.as_mut().unwrap();
+        if let Err(error) = recorder.record(record, &self.state) {
+            error!(
+                "failed to write recording, no longer recording: [{}]",
+                error
+            );
+            self.recorder = None;
+        }
+    }
+}
+
+/// what a projectile goes through
+enum Target<'a> {
+    Enemy(&'a mut api::Enemy),
+    Player(api::PlayerId),
+}
+
+/// The (alive) enemies and players (see `Pvp`) along the ray, nearest first, so
+/// that penetrations are spent in the order they're hit.
+fn targets_in_path<'e>(
+    enemies: &'e mut [api::Enemy],
+    catalog: &api::Catalog,
+    players: &HashMap<api::PlayerId, api::Player>,
+    shooter: api::PlayerId,
+    ray: &api::ProjectileSnaphot,
+    reach: f32,
+) -> Vec<(f32, Target<'e>)> {
+    let players_hit = players_in_path(&catalog.pvp, players, shooter, ray, reach);
+    let mut in_path: Vec<(f32, Target)> = enemies
+        .iter_mut()
+        .filter(|enemy| match enemy.status {
+            api::EnemyStatus::Alive => true,
+            api::EnemyStatus::Dead => false,
+            api::EnemyStatus::Unspecified => {
+                // leave it be, but out of harm's way
+                warn!("enemy [{}] in invalid state, skipping it", enemy.enemy_id);
+                false
+            }
+        })
+        .filter_map(|enemy| {
+            let hitbox = catalog
+                .enemies
+                .get(&enemy.enemy_type)
+                .map_or(FALLBACK_HITBOX, |info| info.hitbox);
+            projectile_ray_hits(ray, &enemy.position, &hitbox, reach)
+                .map(move |distance| (distance, Target::Enemy(enemy)))
+        })
+        .chain(
+            players_hit
+                .into_iter()
+                .map(|(distance, victim)| (distance, Target::Player(victim))),
+        )
+        .collect();
+    // (stable, so enemies before players at the same distance)
+    in_path.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+    in_path
+}
+
+/// the players `shooter` can hit along the ray, and how far along (see `Pvp`)
+fn players_in_path(
+    pvp: &api::Pvp,
+    players: &HashMap<api::PlayerId, api::Player>,
+    shooter: api::PlayerId,
+    ray: &api::ProjectileSnaphot,
+    reach: f32,
+) -> Vec<(f32, api::PlayerId)> {
+    if !pvp.enabled {
+        return vec![];
+    }
+    let shooter_team = players.get(&shooter).and_then(|player| player.team);
+    let mut in_path: Vec<(f32, api::PlayerId)> = players
+        .iter()
+        // (never their own shooter, however it was aimed)
+        .filter(|(id, _)| **id != shooter)
+        .filter(|(_, player)| {
+            matches!(player.connection_status, api::ConnectionStatus::Connected)
+                && player.health > 0
+                && (pvp.friendly_fire || pvp.teams == 0 || player.team != shooter_team)
+        })
+        .filter_map(|(id, player)| {
+            projectile_ray_hits(ray, &player.position, &pvp.player_hitbox, reach)
+                .map(|distance| (distance, *id))
+        })
+        .collect();
+    // (ties by id, so that replays hit the same players)
+    in_path.sort_by(|(a, a_id), (b, b_id)| a.partial_cmp(b).unwrap().then(a_id.cmp(b_id)));
+    in_path
+}
+
+/// (players' health stops at 0)
+fn hit_player(
+    players: &mut HashMap<api::PlayerId, api::Player>,
+    shooter: api::PlayerId,
+    victim: api::PlayerId,
+    damage: api::Health,
+) {
+    let victim = match players.get_mut(&victim) {
+        Some(victim) => victim,
+        None => return,
+    };
+    victim.health = (victim.health - damage).max(0);
+    let killed = victim.health == 0;
+    if let Some(shooter) = players.get_mut(&shooter) {
+        shooter.stats.players_hit += 1;
+        shooter.stats.damage_dealt += damage;
+        if killed {
+            shooter.stats.players_killed += 1;
         }
     }
 }
 
-pub fn projectile_ray_scans_enemy(proj: &api::ProjectileSnaphot, enemy: &api::Enemy) -> bool {
+fn record_hit(stats: &mut api::PlayerStats, damage: api::Health, killed: bool, score_value: u64) {
+    stats.enemies_hit += 1;
+    stats.damage_dealt += damage;
+    if killed {
+        stats.enemies_killed += 1;
+        stats.score += score_value;
+    }
+}
+
+/// how far along the projectile's ray (up to `max_distance`) it hits the hitbox at
+/// `position` (an enemy's or a player's), if it does
+pub fn projectile_ray_hits(
+    proj: &api::ProjectileSnaphot,
+    position: &api::PositionStamped,
+    hitbox: &api::Hitbox,
+    max_distance: f32,
+) -> Option<f32> {
     use nc::math::Isometry;
     use nc::math::Point;
     use nc::query::RayCast;
-    use nc::shape::shape::Shape;
-    use std::f32;
 
-    let (half_width, half_height) = (0.5, 0.5);
-    let enemy_shape = nc::shape::Cuboid::new(Vec2::new(half_width, half_height));
-    let enemy_hit_boundary = enemy_shape.aabb(&Isometry::new(enemy.position.xy, 0f32));
+    let at = Isometry::new(position.xy, 0f32);
     let projectile_ray = nc::query::Ray::new(Point::from(proj.origin.xy), proj.vel.normalize());
     // TODO: this currently shoots through walls, fix that
-    enemy_hit_boundary.intersects_ray(
-        &Isometry::identity(),
-        &projectile_ray,
-        f32::MAX, // e.g. scalar scaling of vector
-    )
+    match *hitbox {
+        api::Hitbox::Cuboid {
+            half_width,
+            half_height,
+        } => nc::shape::Cuboid::new(Vec2::new(half_width, half_height)).toi_with_ray(
+            &at,
+            &projectile_ray,
+            max_distance,
+            true,
+        ),
+        api::Hitbox::Ball { radius } => {
+            nc::shape::Ball::new(radius).toi_with_ray(&at, &projectile_ray, max_distance, true)
+        }
+        api::Hitbox::Capsule {
+            half_height,
+            radius,
+        } => nc::shape::Capsule::new(half_height, radius).toi_with_ray(
+            &at,
+            &projectile_ray,
+            max_distance,
+            true,
+        ),
+    }
+}
+
+/// the connected player nearest to `xy` (the lowest id, among equally near ones)
+fn nearest_connected_player(
+    players: &HashMap<api::PlayerId, api::Player>,
+    xy: Vec2,
+) -> Option<api::PlayerId> {
+    players
+        .iter()
+        .filter(|(_, player)| matches!(player.connection_status, api::ConnectionStatus::Connected))
+        .map(|(id, player)| ((player.position.xy - xy).norm(), *id))
+        // (a position which isn't finite is never the nearest)
+        .filter(|(distance, _)| distance.is_finite())
+        .min_by(|(a, a_id), (b, b_id)| a.partial_cmp(b).unwrap().then(a_id.cmp(b_id)))
+        .map(|(_, id)| id)
+}
+
+/// how much further it reaches
+fn remaining_range(player_proj: &api::PlayerProjectile) -> f32 {
+    match player_proj.current_info.range {
+        Some(range) => range - player_proj.travelled,
+        None => f32::MAX,
+    }
+}
+
+/// each pellet's (unit) direction, fanned out evenly across the spread around `aim`
+fn pellet_directions(
+    aim: Vec2,
+    projectile_info: &api::projectile_info::ProjectileInfo,
+) -> Vec<Vec2> {
+    let pellets = projectile_info.pellets.max(1);
+    if pellets == 1 {
+        return vec![aim];
+    }
+    let spread = projectile_info.spread_degrees.to_radians();
+    (0..pellets)
+        .map(|i| {
+            let angle = -spread / 2.0 + spread * i as f32 / (pellets - 1) as f32;
+            nalgebra::Rotation2::new(angle) * aim
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GameController;
+    use crate::api_types as api;
+    use crate::clock::ManualClock;
+    use crate::gameplay;
+    use crate::geography::GameMap;
+    use crate::intercomm::{self, ChannelUpdate};
+    use crate::metrics::Metrics;
+    use crate::sessions::SessionRegistry;
+
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    /// (some time on 2020, March 25)
+    const START_MS: u64 = 1_585_094_400_000;
+
+    fn game() -> GameController {
+        game_with_clock().0
+    }
+
+    fn game_with_clock() -> (GameController, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(Duration::from_millis(START_MS)));
+        let (_, update_channel_rx) = intercomm::inbox(8);
+        // (never listens, snapshots go nowhere)
+        #[allow(clippy::result_large_err)]
+        let broadcaster = ws::WebSocket::new(|_| |_| Ok(())).unwrap().broadcaster();
+        let game = GameController::new(
+            update_channel_rx,
+            broadcaster,
+            GameMap { max_dimension: 100 },
+            Arc::new(SessionRegistry::new(Duration::from_secs(60))),
+            Arc::new(Metrics::default()),
+            clock.clone(),
+        );
+        (game, clock)
+    }
+
+    fn update(id: api::PlayerId, update: api::ClientUpdate) -> ChannelUpdate {
+        ChannelUpdate { id, update }
+    }
+
+    fn enemy(enemy_id: isize, x: f32, y: f32) -> api::Enemy {
+        api::Enemy {
+            enemy_id,
+            enemy_type: gameplay::GRUNT.to_owned(),
+            position: api::PositionStamped {
+                xy: api::Vec2::new(x, y),
+                time_ms: START_MS,
+            },
+            health: super::ENEMY_STARTING_HEALTH,
+            status: api::EnemyStatus::Alive,
+        }
+    }
+
+    fn projectile(vel: api::Vec2, time_ms: u64) -> api::ProjectileSnaphot {
+        api::ProjectileSnaphot {
+            projectile_type: gameplay::PROJECTILE0.to_owned(),
+            origin: api::PositionStamped {
+                xy: api::Vec2::new(0.0, 0.0),
+                time_ms,
+            },
+            vel,
+        }
+    }
+
+    #[test]
+    fn bad_updates_are_rejected_not_fatal() {
+        let mut game = game();
+        assert!(game
+            .handle_player_update(7, api::ClientUpdate::PlayerDisconnected(()))
+            .is_err());
+
+        game.handle_player_update(1, api::ClientUpdate::PlayerConnected(None))
+            .unwrap();
+        let nowhere = projectile(api::Vec2::new(0.0, 0.0), 0);
+        assert!(game
+            .handle_player_update(1, api::ClientUpdate::ProjectileCreated(nowhere))
+            .is_err());
+        let nan = projectile(api::Vec2::new(f32::NAN, 1.0), 0);
+        assert!(game
+            .handle_player_update(1, api::ClientUpdate::ProjectileCreated(nan))
+            .is_err());
+    }
+
+    #[test]
+    fn projectiles_from_the_future_do_not_underflow() {
+        let mut game = game();
+        let far_future = START_MS + 60_000;
+        // (no shooter, so nothing clamps the timestamp)
+        game.handle_projectile_created(7, projectile(api::Vec2::new(1.0, 0.0), far_future))
+            .unwrap();
+        game.progress_projectiles().unwrap();
+    }
+
+    #[test]
+    fn restores_the_last_completed_tick() {
+        let mut game = game();
+        game.handle_player_update(1, api::ClientUpdate::PlayerConnected(None))
+            .unwrap();
+        game.restore_checkpoint();
+        assert!(game.state.players.is_empty());
+    }
+
+    #[test]
+    fn restoring_carries_the_ticks_control_messages_over_bar_the_culprit() {
+        let mut game = game();
+        game.step(vec![
+            update(1, api::ClientUpdate::PlayerConnected(None)),
+            update(2, api::ClientUpdate::PlayerConnected(None)),
+        ])
+        .unwrap();
+        // (as if the tick panicked upon player 2 connecting)
+        game.handling_control = Some(1);
+        game.restore_checkpoint();
+        assert!(game.state.players.is_empty());
+
+        game.step(vec![]).unwrap();
+        assert!(game.state.players.contains_key(&1));
+        assert!(!game.state.players.contains_key(&2));
+    }
+
+    #[test]
+    fn projectiles_move_with_the_clock() {
+        let (mut game, clock) = game_with_clock();
+        let fired = projectile(api::Vec2::new(1.0, 0.0), START_MS);
+        game.step(vec![
+            update(1, api::ClientUpdate::PlayerConnected(None)),
+            update(1, api::ClientUpdate::ProjectileCreated(fired)),
+        ])
+        .unwrap();
+        let x = |game: &GameController| game.state.projectiles[0].projectile.origin.xy.x;
+        assert_eq!(x(&game), 0.0);
+
+        // (Projectile0 flies at 2 per second)
+        clock.advance(Duration::from_millis(500));
+        game.step(vec![]).unwrap();
+        assert_eq!(x(&game), 1.0);
+        clock.advance(Duration::from_millis(250));
+        game.step(vec![]).unwrap();
+        assert_eq!(x(&game), 1.5);
+        // nothing moves without the clock
+        game.step(vec![]).unwrap();
+        assert_eq!(x(&game), 1.5);
+    }
+
+    #[test]
+    fn projectiles_hit_enemies_in_their_path_once() {
+        let (mut game, clock) = game_with_clock();
+        game.state.enemies.push(enemy(1, 5.0, 0.0));
+        game.state.enemies.push(enemy(2, 5.0, 5.0));
+        let fired = projectile(api::Vec2::new(1.0, 0.0), START_MS);
+        game.step(vec![
+            update(1, api::ClientUpdate::PlayerConnected(None)),
+            update(1, api::ClientUpdate::ProjectileCreated(fired)),
+        ])
+        .unwrap();
+        // (not until it gets there)
+        assert_eq!(game.state.enemies[0].health, super::ENEMY_STARTING_HEALTH);
+        clock.advance(Duration::from_millis(2_000));
+        game.step(vec![]).unwrap();
+        assert_eq!(game.state.enemies[0].health, super::ENEMY_STARTING_HEALTH);
+
+        clock.advance(Duration::from_millis(500));
+        game.step(vec![]).unwrap();
+        assert_eq!(
+            game.state.enemies[0].health,
+            super::ENEMY_STARTING_HEALTH - 10
+        );
+        assert_eq!(game.state.enemies[1].health, super::ENEMY_STARTING_HEALTH);
+        assert_eq!(game.state.players[&1].stats.enemies_hit, 1);
+
+        // (it only penetrates one enemy)
+        clock.advance(Duration::from_millis(1_000));
+        game.step(vec![]).unwrap();
+        assert_eq!(
+            game.state.enemies[0].health,
+            super::ENEMY_STARTING_HEALTH - 10
+        );
+    }
+
+    #[test]
+    fn reloaded_catalogs_schedule_spawns() {
+        let (mut game, clock) = game_with_clock();
+        let (reloads_tx, reloads_rx) = mpsc::channel();
+        game.reload_catalog_from(reloads_rx);
+        game.step(vec![]).unwrap();
+        let mut catalog = gameplay::built_in();
+        catalog.enemies.get_mut(gameplay::GRUNT).unwrap().health = 30;
+        catalog.spawning = api::Spawning {
+            interval_ms: 500,
+            count: 1,
+            max_alive: 3,
+            enemy_type: None,
+        };
+        catalog.waves = vec![api::Wave {
+            after_ms: 1_000,
+            count: 2,
+            enemy_type: None,
+        }];
+        reloads_tx.send(catalog.clone()).unwrap();
+
+        let mut enemies_after_500ms = || {
+            clock.advance(Duration::from_millis(500));
+            game.step(vec![]).unwrap();
+            game.state.enemies.len()
+        };
+        assert_eq!(enemies_after_500ms(), 1);
+        // (the wave, then nothing more while 3 are alive)
+        assert_eq!(enemies_after_500ms(), 3);
+        assert_eq!(enemies_after_500ms(), 3);
+        assert_eq!(game.catalog(), &catalog);
+        assert!(game.state.enemies.iter().all(|enemy| enemy.health == 30));
+    }
+
+    #[test]
+    fn pellets_fan_out_within_range_and_fire_rate() {
+        let (mut game, clock) = game_with_clock();
+        let mut catalog = gameplay::built_in();
+        let shotgun = api::projectile_info::ProjectileInfo {
+            speed: None,
+            damage: 5,
+            num_penetrations: Some(1),
+            range: Some(10.0),
+            fire_rate: Some(1.0),
+            spread_degrees: 90.0,
+            pellets: 3,
+        };
+        catalog.projectiles.insert("SHOTGUN".to_owned(), shotgun);
+        game.set_catalog(catalog);
+        // ahead, 45° to the left, 45° to the right but out of range, and behind the first
+        for (enemy_id, (x, y)) in [(5.0, 0.0), (5.0, 5.0), (8.0, -8.0), (7.0, 0.0)]
+            .iter()
+            .enumerate()
+        {
+            game.state.enemies.push(enemy(enemy_id as isize, *x, *y));
+        }
+        let fire = |time_ms| {
+            let blast = api::ProjectileSnaphot {
+                projectile_type: "SHOTGUN".to_owned(),
+                ..projectile(api::Vec2::new(1.0, 0.0), time_ms)
+            };
+            update(1, api::ClientUpdate::ProjectileCreated(blast))
+        };
+        let health = |game: &GameController| -> Vec<api::Health> {
+            game.state
+                .enemies
+                .iter()
+                .map(|enemy| enemy.health)
+                .collect()
+        };
+
+        let connect = update(1, api::ClientUpdate::PlayerConnected(None));
+        game.step(vec![connect, fire(START_MS), fire(START_MS)])
+            .unwrap();
+        assert_eq!(health(&game), vec![95, 95, 100, 100]);
+        // (the second shot was too soon)
+        assert_eq!(game.state.players[&1].stats.shots_fired, 1);
+        clock.advance(Duration::from_secs(1));
+        game.step(vec![fire(START_MS + 1_000)]).unwrap();
+        assert_eq!(health(&game), vec![90, 90, 100, 100]);
+    }
+
+    #[test]
+    fn enemy_types_have_their_own_hitboxes_and_score() {
+        let (mut game, clock) = game_with_clock();
+        let mut catalog = gameplay::built_in();
+        let mut blob = catalog.enemies[gameplay::GRUNT].clone();
+        blob.hitbox = api::Hitbox::Ball { radius: 2.0 };
+        blob.score_value = 50;
+        catalog.enemies.insert("BLOB".to_owned(), blob);
+        game.set_catalog(catalog);
+        // (a grunt's half-unit box would be missed by both)
+        game.state.enemies.push(enemy(1, 5.0, 1.5));
+        game.state.enemies.push(api::Enemy {
+            enemy_type: "BLOB".to_owned(),
+            health: 5,
+            ..enemy(2, 10.0, 1.5)
+        });
+
+        let fired = api::ProjectileSnaphot {
+            projectile_type: gameplay::HIT_SCAN0.to_owned(),
+            ..projectile(api::Vec2::new(1.0, 0.0), START_MS)
+        };
+        let player = update(1, api::ClientUpdate::PlayerConnected(None));
+        game.step(vec![
+            player,
+            update(1, api::ClientUpdate::ProjectileCreated(fired)),
+        ])
+        .unwrap();
+        assert_eq!(game.state.enemies[0].health, super::ENEMY_STARTING_HEALTH);
+        assert_eq!(game.state.enemies[1].health, 4);
+
+        game.state.enemies[1].health = 1;
+        game.handle_projectile_created(1, projectile(api::Vec2::new(1.0, 0.0), START_MS))
+            .unwrap();
+        clock.advance(Duration::from_millis(5_000));
+        game.step(vec![]).unwrap();
+        assert_eq!(game.state.enemies[1].status, api::EnemyStatus::Dead);
+        assert_eq!(game.state.players[&1].stats.score, 50);
+    }
+
+    #[test]
+    fn chasers_close_in_and_attack_once_a_second() {
+        let (mut game, clock) = game_with_clock();
+        let mut catalog = gameplay::built_in();
+        let grunt = catalog.enemies.get_mut(gameplay::GRUNT).unwrap();
+        grunt.behavior = api::Behavior::Chase;
+        grunt.speed = 2.0;
+        grunt.attack_range = 1.0;
+        grunt.damage = 5;
+        game.set_catalog(catalog);
+        game.state.enemies.push(enemy(1, 4.0, 0.0));
+        game.step(vec![update(1, api::ClientUpdate::PlayerConnected(None))])
+            .unwrap();
+        let x = |game: &GameController| game.state.enemies[0].position.xy.x;
+
+        clock.advance(Duration::from_millis(500));
+        game.step(vec![]).unwrap();
+        assert_eq!(x(&game), 3.0);
+        assert_eq!(game.state.players[&1].health, super::PLAYER_STARTING_HEALTH);
+        // (it stops within range, and attacks)
+        clock.advance(Duration::from_secs(2));
+        game.step(vec![]).unwrap();
+        assert_eq!(x(&game), 1.0);
+        assert_eq!(
+            game.state.players[&1].health,
+            super::PLAYER_STARTING_HEALTH - 5
+        );
+        clock.advance(Duration::from_millis(500));
+        game.step(vec![]).unwrap();
+        assert_eq!(
+            game.state.players[&1].health,
+            super::PLAYER_STARTING_HEALTH - 5
+        );
+        clock.advance(Duration::from_millis(500));
+        game.step(vec![]).unwrap();
+        assert_eq!(
+            game.state.players[&1].health,
+            super::PLAYER_STARTING_HEALTH - 10
+        );
+    }
+
+    #[test]
+    fn pvp_hits_opponents_but_never_the_shooter() {
+        let mut game = game();
+        let mut catalog = gameplay::built_in();
+        catalog.pvp.enabled = true;
+        catalog.pvp.teams = 2;
+        game.set_catalog(catalog.clone());
+        let at = |id, x| {
+            let position = api::PositionStamped {
+                xy: api::Vec2::new(x, 0.0),
+                time_ms: START_MS,
+            };
+            update(id, api::ClientUpdate::PositionUpdate(position))
+        };
+        let connect = |id| update(id, api::ClientUpdate::PlayerConnected(None));
+        // (1 and 3 are teammates, 2 is their opponent)
+        game.step(vec![connect(1), connect(2), connect(3)]).unwrap();
+        game.step(vec![at(1, 0.0), at(3, 3.0), at(2, 6.0)]).unwrap();
+        let teams: Vec<Option<u32>> = (1..=3).map(|id| game.state.players[&id].team).collect();
+        assert_eq!(teams, vec![Some(0), Some(1), Some(0)]);
+
+        let fire = || {
+            let shot = api::ProjectileSnaphot {
+                projectile_type: gameplay::HIT_SCAN0.to_owned(),
+                ..projectile(api::Vec2::new(1.0, 0.0), START_MS)
+            };
+            update(1, api::ClientUpdate::ProjectileCreated(shot))
+        };
+        let health = |game: &GameController| -> Vec<api::Health> {
+            (1..=3).map(|id| game.state.players[&id].health).collect()
+        };
+        game.step(vec![fire()]).unwrap();
+        assert_eq!(health(&game), vec![100, 99, 100]);
+        assert_eq!(game.state.players[&1].stats.players_hit, 1);
+
+        // (the teammate is nearer)
+        catalog.pvp.friendly_fire = true;
+        game.set_catalog(catalog);
+        game.step(vec![fire()]).unwrap();
+        assert_eq!(health(&game), vec![100, 99, 99]);
+        assert_eq!(game.state.players[&1].stats.enemies_hit, 0);
+    }
+
+    #[test]
+    fn moving_projectiles_hit_players_and_enemies_nearest_first() {
+        let (mut game, clock) = game_with_clock();
+        let mut catalog = gameplay::built_in();
+        catalog.pvp.enabled = true;
+        game.set_catalog(catalog);
+        let at_3 = api::PositionStamped {
+            xy: api::Vec2::new(3.0, 0.0),
+            time_ms: START_MS,
+        };
+        game.step(vec![
+            update(1, api::ClientUpdate::PlayerConnected(None)),
+            update(2, api::ClientUpdate::PlayerConnected(None)),
+            update(2, api::ClientUpdate::PositionUpdate(at_3)),
+        ])
+        .unwrap();
+        game.state.enemies.push(enemy(1, 6.0, 0.0));
+
+        // (Projectile0 penetrates once, so only the nearer of the two is hit)
+        let fired = projectile(api::Vec2::new(1.0, 0.0), START_MS);
+        game.step(vec![update(1, api::ClientUpdate::ProjectileCreated(fired))])
+            .unwrap();
+        assert_eq!(game.state.players[&2].health, super::PLAYER_STARTING_HEALTH);
+        for _ in 0..4 {
+            clock.advance(Duration::from_millis(1_000));
+            game.step(vec![]).unwrap();
+        }
+        assert_eq!(
+            game.state.players[&2].health,
+            super::PLAYER_STARTING_HEALTH - 10
+        );
+        assert_eq!(game.state.enemies[0].health, super::ENEMY_STARTING_HEALTH);
+        assert_eq!(game.state.players[&1].stats.players_hit, 1);
+    }
+
+    #[test]
+    fn penetrating_projectiles_hit_each_enemy_once() {
+        let (mut game, clock) = game_with_clock();
+        let mut catalog = gameplay::built_in();
+        catalog
+            .projectiles
+            .get_mut(gameplay::PROJECTILE0)
+            .unwrap()
+            .num_penetrations = Some(3);
+        game.set_catalog(catalog);
+        game.state.enemies.push(enemy(1, 3.0, 0.0));
+        game.state.enemies.push(enemy(2, 6.0, 0.0));
+        let fired = projectile(api::Vec2::new(1.0, 0.0), START_MS);
+        game.step(vec![
+            update(1, api::ClientUpdate::PlayerConnected(None)),
+            update(1, api::ClientUpdate::ProjectileCreated(fired)),
+        ])
+        .unwrap();
+
+        // (it's still inside the first one on the way to the second)
+        clock.advance(Duration::from_millis(1_500));
+        game.step(vec![]).unwrap();
+        clock.advance(Duration::from_millis(1_500));
+        game.step(vec![]).unwrap();
+        let health: Vec<_> = game.state.enemies.iter().map(|e| e.health).collect();
+        assert_eq!(
+            health,
+            vec![
+                super::ENEMY_STARTING_HEALTH - 10,
+                super::ENEMY_STARTING_HEALTH - 10
+            ]
+        );
+        assert_eq!(game.state.players[&1].stats.enemies_hit, 2);
+        assert_eq!(
+            game.state.projectiles[0].current_info.num_penetrations,
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn unlimited_penetrations_hit_each_enemy_once() {
+        let (mut game, clock) = game_with_clock();
+        let mut catalog = gameplay::built_in();
+        catalog
+            .projectiles
+            .get_mut(gameplay::PROJECTILE0)
+            .unwrap()
+            .num_penetrations = None;
+        game.set_catalog(catalog);
+        game.state.enemies.push(enemy(1, 1.0, 0.0));
+        game.state.enemies.push(enemy(2, 3.0, 0.0));
+        let fired = projectile(api::Vec2::new(1.0, 0.0), START_MS);
+        game.step(vec![
+            update(1, api::ClientUpdate::PlayerConnected(None)),
+            update(1, api::ClientUpdate::ProjectileCreated(fired)),
+        ])
+        .unwrap();
+
+        // (ticks short enough for it to be inside each enemy for a few of them)
+        for _ in 0..20 {
+            clock.advance(Duration::from_millis(100));
+            game.step(vec![]).unwrap();
+        }
+        let health: Vec<_> = game.state.enemies.iter().map(|e| e.health).collect();
+        assert_eq!(
+            health,
+            vec![
+                super::ENEMY_STARTING_HEALTH - 10,
+                super::ENEMY_STARTING_HEALTH - 10
+            ]
+        );
+        assert_eq!(game.state.players[&1].stats.enemies_hit, 2);
+    }
+
+    #[test]
+    fn positions_outside_the_map_are_rejected() {
+        let mut game = game();
+        game.handle_player_update(1, api::ClientUpdate::PlayerConnected(None))
+            .unwrap();
+        let move_to = |game: &mut GameController, x: f32| {
+            let position = api::PositionStamped {
+                xy: api::Vec2::new(x, 0.0),
+                time_ms: START_MS,
+            };
+            game.handle_player_update(1, api::ClientUpdate::PositionUpdate(position))
+        };
+        for x in [f32::INFINITY, f32::NAN, -1.0, 101.0] {
+            assert!(move_to(&mut game, x).is_err(), "{}", x);
+        }
+        assert_eq!(game.state.players[&1].position.xy, api::Vec2::new(0.0, 0.0));
+        move_to(&mut game, 100.0).unwrap();
+        assert_eq!(
+            game.state.players[&1].position.xy,
+            api::Vec2::new(100.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn projectiles_expire_at_their_range() {
+        let (mut game, clock) = game_with_clock();
+        let mut catalog = gameplay::built_in();
+        // (2 per second)
+        catalog
+            .projectiles
+            .get_mut(gameplay::PROJECTILE0)
+            .unwrap()
+            .range = Some(3.0);
+        game.set_catalog(catalog);
+        let fired = projectile(api::Vec2::new(1.0, 0.0), START_MS);
+        game.step(vec![update(1, api::ClientUpdate::ProjectileCreated(fired))])
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        game.step(vec![]).unwrap();
+        assert_eq!(game.state.projectiles[0].travelled, 2.0);
+        clock.advance(Duration::from_secs(1));
+        game.step(vec![]).unwrap();
+        assert!(game.state.projectiles.is_empty());
+    }
+
+    #[test]
+    fn projectiles_are_gone_once_spent_or_off_the_map() {
+        let (mut game, clock) = game_with_clock();
+        game.state.enemies.push(enemy(1, 3.0, 0.0));
+        let fired = projectile(api::Vec2::new(1.0, 0.0), START_MS);
+        game.step(vec![
+            update(1, api::ClientUpdate::PlayerConnected(None)),
+            update(1, api::ClientUpdate::ProjectileCreated(fired)),
+        ])
+        .unwrap();
+        // (Projectile0 penetrates once)
+        clock.advance(Duration::from_secs(2));
+        game.step(vec![]).unwrap();
+        assert_eq!(
+            game.state.enemies[0].health,
+            super::ENEMY_STARTING_HEALTH - 10
+        );
+        assert!(game.state.projectiles.is_empty());
+
+        let away = projectile(api::Vec2::new(-1.0, 0.0), START_MS + 2_000);
+        game.step(vec![update(1, api::ClientUpdate::ProjectileCreated(away))])
+            .unwrap();
+        assert_eq!(game.state.projectiles.len(), 1);
+        clock.advance(Duration::from_secs(1));
+        game.step(vec![]).unwrap();
+        assert!(game.state.projectiles.is_empty());
+    }
 }
@@ -0,0 +1,338 @@
+use crate::api_types as api;
+use crate::game_control::ENEMY_STARTING_HEALTH;
+use crate::utils::CancellationToken;
+use api::projectile_info::ProjectileInfo;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/* The gameplay catalog: what weapons and enemies are like, and how enemies
+spawn (see `api::Catalog`), as a toml file (see `config::GAMEPLAY_PATH`):
+    [projectiles.PROJECTILE0]
+    speed = 2.0
+    damage = 10
+    num_penetrations = 1
+    range = 30.0    (and fire_rate, pellets, spread_degrees, see `ProjectileInfo`)
+
+    [enemies.GRUNT]
+    health = 100
+    speed = 1.5
+    hitbox = { shape = "BALL", radius = 0.5 }    (or CUBOID, or CAPSULE, see `api::Hitbox`)
+    damage = 5
+    attack_range = 1.0
+    score_value = 10
+    behavior = "CHASE"
+
+    [spawning]
+    interval_ms = 10_000
+    count = 2
+    max_alive = 20
+
+    [[waves]]
+    after_ms = 60_000
+    count = 5
+
+    [pvp]
+    enabled = true
+    teams = 2    (or 0, for every player for themselves)
+    friendly_fire = false
+    player_hitbox = { shape = "CAPSULE", half_height = 0.5, radius = 0.3 }
+
+(`gameplay.toml` at the root of the repository is the built-in catalog.)
+
+The file is watched (see `watch`): whenever it changes, or upon SIGHUP, it's
+reloaded, and if it's valid, the game controller switches to it between two
+ticks and sends it to every client. An invalid file is logged and ignored, so
+a typo mid-match leaves the game as it was. Clients also get the catalog upon
+connecting (see `SharedCatalog`), so a new weapon is just a new entry.
+
+Projectiles already in flight keep the stats they were fired with.
+*/
+
+/// how often the file is checked for changes (and for SIGHUP)
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the built-in catalog's projectiles
+pub const HIT_SCAN0: &str = "HIT_SCAN0";
+pub const PROJECTILE0: &str = "PROJECTILE0";
+/// the built-in catalog's (only) enemy
+pub const GRUNT: &str = "GRUNT";
+
+/// as before there were catalog files
+pub fn built_in() -> api::Catalog {
+    let single_shot = |speed, damage| ProjectileInfo {
+        speed,
+        damage,
+        num_penetrations: Some(1),
+        range: None,
+        fire_rate: None,
+        spread_degrees: 0.0,
+        pellets: 1,
+    };
+    api::Catalog {
+        projectiles: vec![
+            (HIT_SCAN0.to_owned(), single_shot(None, 1)),
+            (PROJECTILE0.to_owned(), single_shot(Some(2.0), 10)),
+        ]
+        .into_iter()
+        .collect(),
+        enemies: vec![(
+            GRUNT.to_owned(),
+            api::EnemyInfo {
+                health: ENEMY_STARTING_HEALTH,
+                speed: 0.0,
+                hitbox: api::Hitbox::Cuboid {
+                    half_width: 0.5,
+                    half_height: 0.5,
+                },
+                damage: 0,
+                attack_range: 0.0,
+                score_value: 10,
+                behavior: api::Behavior::Stationary,
+            },
+        )]
+        .into_iter()
+        .collect(),
+        spawning: api::Spawning::default(),
+        waves: vec![],
+        pvp: api::Pvp::default(),
+    }
+}
+
+/// what's wrong with `catalog`, if anything
+pub fn problems(catalog: &api::Catalog) -> Vec<String> {
+    let mut problems = vec![];
+    if catalog.projectiles.is_empty() {
+        problems.push("there are no projectiles".to_owned());
+    }
+    let positive = |value: Option<f32>| value.map(|value| value.is_finite() && value > 0.0);
+    for (id, info) in &catalog.projectiles {
+        let mut require = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(format!("projectile [{}] {}", id, problem));
+            }
+        };
+        require(!id.is_empty(), "needs a name");
+        require(
+            positive(info.speed) != Some(false),
+            "needs a positive speed (or none, for hitscan)",
+        );
+        require(info.damage >= 0, "has negative damage");
+        require(
+            info.num_penetrations.unwrap_or(1) >= 1,
+            "must penetrate at least one enemy",
+        );
+        require(
+            positive(info.range) != Some(false),
+            "needs a positive range (or none)",
+        );
+        require(
+            positive(info.fire_rate) != Some(false),
+            "needs a positive fire_rate (or none)",
+        );
+        require(
+            (0.0..=360.0).contains(&info.spread_degrees),
+            "needs a spread_degrees between 0 and 360",
+        );
+        require(info.pellets >= 1, "needs at least one pellet");
+    }
+    if catalog.enemies.is_empty() {
+        problems.push("there are no enemies".to_owned());
+    }
+    let at_least = |minimum: f32, value: f32| value.is_finite() && value >= minimum;
+    for (id, info) in &catalog.enemies {
+        let mut require = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(format!("enemy [{}] {}", id, problem));
+            }
+        };
+        require(!id.is_empty(), "needs a name");
+        require(info.health > 0, "needs positive health");
+        require(at_least(0.0, info.speed), "has a negative speed");
+        require(
+            is_sound(&info.hitbox),
+            "needs a hitbox of positive dimensions",
+        );
+        require(info.damage >= 0, "has negative damage");
+        require(
+            at_least(0.0, info.attack_range),
+            "has a negative attack_range",
+        );
+    }
+    if catalog.spawning.interval_ms > 0 && catalog.spawning.count == 0 {
+        problems.push("spawning every interval_ms needs a positive count".to_owned());
+    }
+    let spawned_types = std::iter::once(&catalog.spawning.enemy_type)
+        .chain(catalog.waves.iter().map(|wave| &wave.enemy_type))
+        .filter_map(Option::as_ref);
+    for enemy_type in spawned_types {
+        if !catalog.enemies.contains_key(enemy_type) {
+            problems.push(format!("no such enemy [{}] to spawn", enemy_type));
+        }
+    }
+    if catalog.pvp.teams == 1 {
+        problems.push("pvp needs 0 teams, or 2 or more".to_owned());
+    }
+    if !is_sound(&catalog.pvp.player_hitbox) {
+        problems.push("pvp needs a player_hitbox of positive dimensions".to_owned());
+    }
+    problems
+}
+
+/// whether all its dimensions are positive
+fn is_sound(hitbox: &api::Hitbox) -> bool {
+    let dimensions = match *hitbox {
+        api::Hitbox::Cuboid {
+            half_width,
+            half_height,
+        } => vec![half_width, half_height],
+        api::Hitbox::Ball { radius } => vec![radius],
+        api::Hitbox::Capsule {
+            half_height,
+            radius,
+        } => vec![half_height, radius],
+    };
+    dimensions.iter().all(|&d| d.is_finite() && d > 0.0)
+}
+
+/// from a toml catalog file, validated
+pub fn load(path: &str) -> Result<api::Catalog, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|error| format!("cannot read gameplay catalog [{}]: {}", path, error))?;
+    let catalog: api::Catalog = toml::from_str(&text)
+        .map_err(|error| format!("bad gameplay catalog [{}]: {}", path, error))?;
+    let problems = problems(&catalog);
+    if !problems.is_empty() {
+        return Err(format!(
+            "bad gameplay catalog [{}]: {}",
+            path,
+            problems.join("; ")
+        ));
+    }
+    Ok(catalog)
+}
+
+/// Reloads the catalog at `path` whenever it changes (or upon SIGHUP), sending
+/// each valid one to `reloads` (see `GameController::reload_catalog_from`),
+/// until cancelled.
+pub fn watch(
+    path: String,
+    reloads: mpsc::Sender<api::Catalog>,
+    cancellation: CancellationToken,
+) -> std::io::Result<thread::JoinHandle<()>> {
+    let hangup = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGHUP, hangup.clone())?;
+    let mut last_modified = modified(&path);
+    thread::Builder::new()
+        .name("GameplayWatcher".to_owned())
+        .spawn(move || {
+            while !cancellation.wait_timeout(WATCH_INTERVAL) {
+                let hung_up = hangup.swap(false, Ordering::Relaxed);
+                let now_modified = modified(&path);
+                if !hung_up && now_modified == last_modified {
+                    continue;
+                }
+                last_modified = now_modified;
+                match load(&path) {
+                    Ok(catalog) => {
+                        info!("reloaded gameplay catalog [{}]", path);
+                        if reloads.send(catalog).is_err() {
+                            // (the game controller is gone)
+                            return;
+                        }
+                    }
+                    Err(error) => warn!("{}, keeping the current one", error),
+                }
+            }
+        })
+}
+
+/// The catalog in play, as sent to clients upon connecting; the game controller
+/// keeps it up to date (see `GameController::share_catalog_with`).
+#[derive(Debug)]
+pub struct SharedCatalog {
+    /// (the CATALOG message, ready to send)
+    message: RwLock<String>,
+    /// (for checking admin commands)
+    enemy_types: RwLock<Vec<api::EnemyType>>,
+}
+
+impl SharedCatalog {
+    pub fn new(catalog: &api::Catalog) -> SharedCatalog {
+        SharedCatalog {
+            message: RwLock::new(catalog_message(catalog)),
+            enemy_types: RwLock::new(catalog.enemies.keys().cloned().collect()),
+        }
+    }
+
+    pub fn set(&self, catalog: &api::Catalog) {
+        *self.message.write().unwrap() = catalog_message(catalog);
+        *self.enemy_types.write().unwrap() = catalog.enemies.keys().cloned().collect();
+    }
+
+    pub fn has_enemy_type(&self, enemy_type: &str) -> bool {
+        self.enemy_types
+            .read()
+            .unwrap()
+            .iter()
+            .any(|t| t == enemy_type)
+    }
+
+    /// the CATALOG message
+    pub fn message(&self) -> String {
+        self.message.read().unwrap().clone()
+    }
+}
+
+/// the CATALOG message for `catalog`
+pub fn catalog_message(catalog: &api::Catalog) -> String {
+    serde_json::ser::to_string(&api::ServerUpdate::Catalog(catalog.clone())).unwrap()
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{built_in, problems};
+    use crate::api_types as api;
+
+    #[test]
+    fn the_example_file_is_the_built_in_catalog() {
+        let example: api::Catalog = toml::from_str(include_str!("../gameplay.toml")).unwrap();
+        assert_eq!(example, built_in());
+        assert!(problems(&example).is_empty());
+    }
+
+    #[test]
+    fn nonsense_is_refused() {
+        let mut catalog = built_in();
+        let projectile = catalog.projectiles.get_mut(super::PROJECTILE0).unwrap();
+        projectile.speed = Some(0.0);
+        projectile.pellets = 0;
+        catalog.enemies.get_mut(super::GRUNT).unwrap().health = 0;
+        catalog.waves.push(api::Wave {
+            after_ms: 0,
+            count: 1,
+            enemy_type: Some("GHOST".to_owned()),
+        });
+        catalog.pvp.teams = 1;
+        assert_eq!(problems(&catalog).len(), 5);
+        catalog.projectiles.clear();
+        assert!(problems(&catalog).contains(&"there are no projectiles".to_owned()));
+
+        // (new weapons are just data, but typos are caught)
+        let laser = "[projectiles.LASER]\ndamage = 1\nnum_penetrations = 3\n";
+        let bat = "[enemies.BAT]\nhealth = 1\nhitbox = { shape = \"BALL\", radius = 0.2 }\n";
+        let laser: api::Catalog = toml::from_str(&format!("{}{}", laser, bat)).unwrap();
+        assert!(problems(&laser).is_empty());
+        assert_eq!(laser.projectiles["LASER"].pellets, 1);
+        assert_eq!(laser.enemies["BAT"].behavior, api::Behavior::Stationary);
+        assert!(toml::from_str::<api::Catalog>("[projectiles.LASER]\ndamge = 1\n").is_err());
+    }
+}
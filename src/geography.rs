@@ -1,4 +1,25 @@
-#[derive(Debug)]
+use serde::Deserialize;
+
+/// (also the format of map files, see `config::MAP_PATH`)
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GameMap {
     pub max_dimension: usize,
 }
+
+impl GameMap {
+    /// from a toml map file
+    pub fn load(path: &str) -> Result<GameMap, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| format!("cannot read map [{}]: {}", path, error))?;
+        let map: GameMap =
+            toml::from_str(&text).map_err(|error| format!("bad map [{}]: {}", path, error))?;
+        if map.max_dimension == 0 {
+            return Err(format!(
+                "bad map [{}]: max_dimension must be positive",
+                path
+            ));
+        }
+        Ok(map)
+    }
+}
@@ -0,0 +1,127 @@
+use serde::Serialize;
+
+use std::time::Duration;
+
+/// plain http probe: is the game running at all
+pub const HEALTH_PATH: &str = "/healthz";
+/// plain http probe: should new players be sent here
+pub const READY_PATH: &str = "/readyz";
+
+/// what the probes are judged on, gathered by the server
+#[derive(Debug)]
+pub struct Observations {
+    pub controller_alive: bool,
+    /// None if the game controller hasn't completed a tick yet
+    pub last_tick_age: Option<Duration>,
+    pub max_tick_age: Duration,
+    pub connections: usize,
+    pub capacity: usize,
+    pub shutting_down: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub status: &'static str,
+    pub controller_alive: bool,
+    pub last_tick_age_ms: Option<u64>,
+    pub ticking: bool,
+    pub connections: usize,
+    pub capacity: usize,
+    pub shutting_down: bool,
+}
+
+impl Observations {
+    fn ticking(&self) -> bool {
+        match self.last_tick_age {
+            Some(age) => age <= self.max_tick_age,
+            None => false,
+        }
+    }
+
+    /// healthy: the game controller is alive and still ticking
+    pub fn healthy(&self) -> bool {
+        self.controller_alive && self.ticking()
+    }
+
+    /// ready: healthy, not shutting down, and with room for another player
+    pub fn ready(&self) -> bool {
+        self.healthy() && !self.shutting_down && self.connections < self.capacity
+    }
+
+    pub fn report(&self, ok: bool) -> Report {
+        Report {
+            status: if ok { "ok" } else { "unavailable" },
+            controller_alive: self.controller_alive,
+            last_tick_age_ms: self.last_tick_age.map(|age| age.as_millis() as u64),
+            ticking: self.ticking(),
+            connections: self.connections,
+            capacity: self.capacity,
+            shutting_down: self.shutting_down,
+        }
+    }
+}
+
+/// 200 with the report if `ok`, 503 otherwise (load balancers only look at the status)
+pub fn response(observations: &Observations, ok: bool) -> ws::Response {
+    let body = serde_json::ser::to_vec(&observations.report(ok)).unwrap();
+    let mut response = if ok {
+        ws::Response::new(200, "OK", body)
+    } else {
+        ws::Response::new(503, "Service Unavailable", body)
+    };
+    response
+        .headers_mut()
+        .push(("Content-Type".to_owned(), b"application/json".to_vec()));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Observations;
+
+    use std::time::Duration;
+
+    fn observations() -> Observations {
+        Observations {
+            controller_alive: true,
+            last_tick_age: Some(Duration::from_millis(20)),
+            max_tick_age: Duration::from_millis(1_000),
+            connections: 1,
+            capacity: 2,
+            shutting_down: false,
+        }
+    }
+
+    #[test]
+    fn healthy_needs_a_recent_tick() {
+        assert!(observations().healthy());
+        let stalled = Observations {
+            last_tick_age: Some(Duration::from_secs(5)),
+            ..observations()
+        };
+        assert!(!stalled.healthy());
+        let dead = Observations {
+            controller_alive: false,
+            ..observations()
+        };
+        assert!(!dead.healthy());
+        assert_eq!(dead.report(false).status, "unavailable");
+    }
+
+    #[test]
+    fn ready_needs_capacity() {
+        assert!(observations().ready());
+        let full = Observations {
+            connections: 2,
+            ..observations()
+        };
+        assert!(full.healthy());
+        assert!(!full.ready());
+        let draining = Observations {
+            shutting_down: true,
+            ..observations()
+        };
+        assert!(!draining.ready());
+    }
+}
@@ -1,6 +1,265 @@
 use crate::api_types as api;
 
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
 pub struct ChannelUpdate {
     pub id: api::PlayerId,
     pub update: api::ClientUpdate,
 }
+
+impl ChannelUpdate {
+    /// control messages change who is in the game (or how it runs), and must never be lost
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self.update,
+            api::ClientUpdate::PlayerConnected(_)
+                | api::ClientUpdate::PlayerDisconnected(_)
+                | api::ClientUpdate::Admin(_)
+        )
+    }
+}
+
+/// The game controller's inbox, fed by the server threads.
+///
+/// Messages are kept in three lanes, drained in this order every tick:
+///   1. control messages (connect/disconnect/admin), unbounded but as rare as connections
+///   2. position updates, coalesced to the latest per player (only that one matters)
+///   3. everything else, in arrival order, up to `capacity`; beyond that,
+///      senders are refused (`SendError::Full`) rather than piling up latency
+pub fn inbox(capacity: usize) -> (InboxSender, InboxReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(InboxState::default()),
+        available: Condvar::new(),
+        capacity,
+        metrics: InboxMetrics::default(),
+    });
+    (
+        InboxSender {
+            shared: shared.clone(),
+        },
+        InboxReceiver { shared },
+    )
+}
+
+struct Shared {
+    state: Mutex<InboxState>,
+    available: Condvar,
+    capacity: usize,
+    metrics: InboxMetrics,
+}
+
+#[derive(Default)]
+struct InboxState {
+    control: VecDeque<ChannelUpdate>,
+    positions: HashMap<api::PlayerId, api::PositionStamped>,
+    updates: VecDeque<ChannelUpdate>,
+    woken: bool,
+    receiver_gone: bool,
+}
+
+impl InboxState {
+    fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.positions.is_empty() && self.updates.is_empty()
+    }
+}
+
+/// how the inbox is keeping up, for the server threads (and whoever monitors them)
+#[derive(Debug, Default)]
+pub struct InboxMetrics {
+    /// messages currently waiting in the bounded lane
+    pub queued: AtomicU64,
+    /// most messages ever waiting in the bounded lane
+    pub queued_high_water: AtomicU64,
+    /// refused because the bounded lane was full
+    pub rejected: AtomicU64,
+    /// position updates replaced by a newer one before being applied
+    pub positions_coalesced: AtomicU64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// the game controller is falling behind
+    Full,
+    /// the game controller is gone (e.g. shutting down)
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full => write!(f, "game controller inbox full"),
+            SendError::Closed => write!(f, "game controller inbox closed"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct InboxSender {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for InboxSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InboxSender")
+            .field("metrics", &self.shared.metrics)
+            .finish()
+    }
+}
+
+impl InboxSender {
+    pub fn send(&self, message: ChannelUpdate) -> Result<(), SendError> {
+        let metrics = &self.shared.metrics;
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receiver_gone {
+            return Err(SendError::Closed);
+        }
+        if message.is_control() {
+            state.control.push_back(message);
+        } else if let api::ClientUpdate::PositionUpdate(position) = message.update {
+            if state.positions.insert(message.id, position).is_some() {
+                metrics.positions_coalesced.fetch_add(1, Ordering::Relaxed);
+            }
+        } else {
+            if state.updates.len() >= self.shared.capacity {
+                metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(SendError::Full);
+            }
+            state.updates.push_back(message);
+            let queued = state.updates.len() as u64;
+            metrics.queued.store(queued, Ordering::Relaxed);
+            metrics
+                .queued_high_water
+                .fetch_max(queued, Ordering::Relaxed);
+        }
+        self.shared.available.notify_one();
+        Ok(())
+    }
+
+    /// carries nothing, only wakes the game controller (e.g. upon cancellation)
+    pub fn wake(&self) {
+        self.shared.state.lock().unwrap().woken = true;
+        self.shared.available.notify_one();
+    }
+
+    pub fn metrics(&self) -> &InboxMetrics {
+        &self.shared.metrics
+    }
+}
+
+pub struct InboxReceiver {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for InboxReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InboxReceiver")
+            .field("metrics", &self.shared.metrics)
+            .finish()
+    }
+}
+
+impl InboxReceiver {
+    /// Waits up to `timeout` for anything to arrive (or a wakeup), then takes
+    /// everything waiting, in lane order.
+    pub fn recv_batch(&self, timeout: Duration) -> Vec<ChannelUpdate> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.is_empty() && !state.woken {
+            state = self
+                .shared
+                .available
+                .wait_timeout_while(state, timeout, |state| state.is_empty() && !state.woken)
+                .unwrap()
+                .0;
+        }
+        state.woken = false;
+        let mut batch: Vec<ChannelUpdate> = state.control.drain(..).collect();
+        batch.extend(state.positions.drain().map(|(id, position)| ChannelUpdate {
+            id,
+            update: api::ClientUpdate::PositionUpdate(position),
+        }));
+        batch.extend(state.updates.drain(..));
+        self.shared.metrics.queued.store(0, Ordering::Relaxed);
+        batch
+    }
+}
+
+impl Drop for InboxReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiver_gone = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inbox, ChannelUpdate, SendError};
+    use crate::api_types as api;
+
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    fn position(id: api::PlayerId, x: f32) -> ChannelUpdate {
+        ChannelUpdate {
+            id,
+            update: api::ClientUpdate::PositionUpdate(api::PositionStamped {
+                xy: api::Vec2::new(x, 0.0),
+                time_ms: 0,
+            }),
+        }
+    }
+
+    fn connected(id: api::PlayerId) -> ChannelUpdate {
+        ChannelUpdate {
+            id,
+            update: api::ClientUpdate::PlayerConnected(None),
+        }
+    }
+
+    #[test]
+    fn control_first_and_latest_position_only() {
+        let (tx, rx) = inbox(8);
+        tx.send(position(1, 1.0)).unwrap();
+        tx.send(position(1, 2.0)).unwrap();
+        tx.send(connected(1)).unwrap();
+
+        let batch = rx.recv_batch(Duration::from_millis(0));
+        assert_eq!(batch.len(), 2);
+        assert!(matches!(
+            batch[0].update,
+            api::ClientUpdate::PlayerConnected(_)
+        ));
+        assert!(match &batch[1].update {
+            api::ClientUpdate::PositionUpdate(position) => position.xy.x == 2.0,
+            _ => false,
+        });
+        assert_eq!(tx.metrics().positions_coalesced.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn refuses_beyond_capacity_but_never_control() {
+        let (tx, rx) = inbox(1);
+        let measured = || ChannelUpdate {
+            id: 1,
+            update: api::ClientUpdate::ConnectionQualityMeasured(api::ConnectionQuality {
+                rtt_mean_ms: 0.0,
+                rtt_stddev_ms: 0.0,
+                rtt_min_ms: 0.0,
+                rtt_max_ms: 0.0,
+                jitter_ms: 0.0,
+                samples: 1,
+                pongs_lost: 0,
+            }),
+        };
+        tx.send(measured()).unwrap();
+        assert_eq!(tx.send(measured()), Err(SendError::Full));
+        tx.send(connected(2)).unwrap();
+        assert_eq!(rx.recv_batch(Duration::from_millis(0)).len(), 2);
+
+        drop(rx);
+        assert_eq!(tx.send(connected(3)), Err(SendError::Closed));
+    }
+}
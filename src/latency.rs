@@ -0,0 +1,130 @@
+use crate::api_types as api;
+
+use stats::{MinMax, OnlineStats};
+
+use std::collections::VecDeque;
+
+/// Rolling round trip time statistics for one connection, over the last
+/// `window_size` ping/pong exchanges, plus a count of pings never answered.
+#[derive(Debug)]
+pub struct LatencyTracker {
+    rtts_ms: VecDeque<f64>,
+    window_size: usize,
+    awaiting_pong: bool,
+    pongs_lost: u64,
+}
+
+impl LatencyTracker {
+    pub fn new(window_size: usize) -> LatencyTracker {
+        LatencyTracker {
+            rtts_ms: VecDeque::with_capacity(window_size),
+            window_size: window_size.max(1),
+            awaiting_pong: false,
+            pongs_lost: 0,
+        }
+    }
+
+    /// a ping still unanswered by the time the next one goes out counts as lost
+    pub fn ping_sent(&mut self) {
+        if self.awaiting_pong {
+            self.pongs_lost += 1;
+        }
+        self.awaiting_pong = true;
+    }
+
+    pub fn pong_received(&mut self, rtt_ms: f64) {
+        self.awaiting_pong = false;
+        if self.rtts_ms.len() == self.window_size {
+            self.rtts_ms.pop_front();
+        }
+        self.rtts_ms.push_back(rtt_ms);
+    }
+
+    /// None until the first pong
+    pub fn quality(&self) -> Option<api::ConnectionQuality> {
+        if self.rtts_ms.is_empty() {
+            return None;
+        }
+        let rtts: Vec<f64> = self.rtts_ms.iter().cloned().collect();
+        let online = OnlineStats::from_slice(&rtts);
+        let mut min_max = MinMax::new();
+        for rtt in rtts.iter() {
+            min_max.add(*rtt);
+        }
+        // mean variation between consecutive samples
+        let jitter = if rtts.len() > 1 {
+            let deltas: Vec<f64> = rtts.windows(2).map(|w| (w[1] - w[0]).abs()).collect();
+            OnlineStats::from_slice(&deltas).mean()
+        } else {
+            0.0
+        };
+        Some(api::ConnectionQuality {
+            rtt_mean_ms: online.mean() as f32,
+            rtt_stddev_ms: online.stddev() as f32,
+            rtt_min_ms: *min_max.min().unwrap() as f32,
+            rtt_max_ms: *min_max.max().unwrap() as f32,
+            jitter_ms: jitter as f32,
+            samples: rtts.len(),
+            pongs_lost: self.pongs_lost,
+        })
+    }
+}
+
+/// How far back in time (ms) a client's timestamps may plausibly lag the
+/// server's, given its connection: roughly the one way trip, with some margin
+/// for variance. Never more than `max_ms`, which is also the allowance for
+/// connections not yet measured.
+pub fn lag_allowance_ms(quality: Option<&api::ConnectionQuality>, max_ms: u64) -> u64 {
+    match quality {
+        Some(q) => {
+            let one_way = (q.rtt_mean_ms + 2.0 * q.rtt_stddev_ms) / 2.0 + q.jitter_ms;
+            (one_way.max(0.0).ceil() as u64).min(max_ms)
+        }
+        None => max_ms,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lag_allowance_ms, LatencyTracker};
+
+    #[test]
+    fn rolling_window_statistics() {
+        let mut tracker = LatencyTracker::new(3);
+        assert!(tracker.quality().is_none());
+
+        for rtt in [100.0, 10.0, 20.0, 30.0].iter() {
+            tracker.ping_sent();
+            tracker.pong_received(*rtt);
+        }
+        let quality = tracker.quality().unwrap();
+        // the 100 fell out of the window
+        assert_eq!(quality.samples, 3);
+        assert_eq!(quality.rtt_mean_ms, 20.0);
+        assert_eq!(quality.rtt_min_ms, 10.0);
+        assert_eq!(quality.rtt_max_ms, 30.0);
+        assert_eq!(quality.jitter_ms, 10.0);
+        assert_eq!(quality.pongs_lost, 0);
+    }
+
+    #[test]
+    fn counts_unanswered_pings() {
+        let mut tracker = LatencyTracker::new(3);
+        tracker.ping_sent();
+        tracker.ping_sent();
+        tracker.ping_sent();
+        tracker.pong_received(5.0);
+        assert_eq!(tracker.quality().unwrap().pongs_lost, 2);
+    }
+
+    #[test]
+    fn lag_allowance_is_capped() {
+        let mut tracker = LatencyTracker::new(4);
+        tracker.pong_received(40.0);
+        tracker.pong_received(40.0);
+        let quality = tracker.quality().unwrap();
+        assert_eq!(lag_allowance_ms(Some(&quality), 250), 20);
+        assert_eq!(lag_allowance_ms(Some(&quality), 10), 10);
+        assert_eq!(lag_allowance_ms(None, 250), 250);
+    }
+}
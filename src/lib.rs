@@ -0,0 +1,26 @@
+pub mod admin;
+pub mod api_types;
+pub mod auth;
+pub mod backpressure;
+pub mod client;
+pub mod clock;
+pub mod config;
+pub mod game_control;
+pub mod gameplay;
+pub mod geography;
+pub mod health;
+pub mod intercomm;
+pub mod latency;
+pub mod metrics;
+pub mod rate_limit;
+pub mod recording;
+pub mod resolver;
+pub mod server;
+pub mod sessions;
+pub mod shutdown;
+pub mod utils;
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate lazy_static;
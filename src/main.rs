@@ -1,55 +1,158 @@
-mod api_types;
-mod config;
-mod game_control;
-mod geography;
-mod intercomm;
-mod server;
-mod utils;
-
-use crate::utils::SerialIdGenerator;
+use tide::{
+    admin, backpressure, clock, config, game_control, gameplay, geography, intercomm, metrics,
+    rate_limit, recording, resolver, server, sessions, shutdown, utils,
+};
 
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate lazy_static;
 
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+/// process exit statuses (deploy scripts rely on these)
+const EXIT_OK: i32 = 0;
+const EXIT_SOCKET_FAILED: i32 = 1;
+const EXIT_GAME_CONTROLLER_FAILED: i32 = 2;
+const EXIT_BAD_CONFIG: i32 = 3;
 
-fn parse_args() -> (String,) {
-    let args: Vec<String> = std::env::args().collect();
-    let socket_address = args[1].clone();
-    (socket_address,)
+/// the configuration (see `config`), exiting upon --help or a bad one
+fn load_config() -> config::ServerConfig {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match config::load(&args) {
+        Ok(config::Command::Run(config)) => *config,
+        Ok(config::Command::Help) => {
+            println!("{}", config::help());
+            std::process::exit(EXIT_OK)
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(EXIT_BAD_CONFIG)
+        }
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    config::init();
-
-    let (socket_address,) = parse_args();
+    env_logger::init();
+    let server_config = load_config();
+    for (name, value) in server_config.summary() {
+        info!("{}=[{}]", name, value);
+    }
+    let map = match &server_config.map_path {
+        Some(path) => geography::GameMap::load(path).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(EXIT_BAD_CONFIG)
+        }),
+        None => geography::GameMap {
+            max_dimension: server_config.map_max_dimension,
+        },
+    };
+    let catalog = match &server_config.gameplay_path {
+        Some(path) => gameplay::load(path).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(EXIT_BAD_CONFIG)
+        }),
+        None => gameplay::built_in(),
+    };
+    let socket_address = server_config.listen_address.clone();
+    config::install(server_config);
     info!("starting server, using address [{}]...", socket_address);
 
     // Create communication channel between websockets servers and
     // the update game_controller.
-    let (update_channel_tx, update_channel_rx) = mpsc::channel();
+    let (update_channel_tx, update_channel_rx) =
+        intercomm::inbox(config::CONTROLLER_INBOX_CAPACITY());
 
     // Configure websockets server(s).
-    let resolver = utils::PlayerIdGenerator::new(1);
-    let (socket, broadcaster) = server::set_up_websockets_server(&update_channel_tx, &resolver);
+    #[cfg(feature = "ip-address-player-ids")]
+    let resolver = resolver::IpAddressPlayerIdResolver::new(1);
+    #[cfg(not(feature = "ip-address-player-ids"))]
+    let resolver = resolver::SequentialPlayerIdResolver::new(1);
+    let sessions = Arc::new(sessions::SessionRegistry::new(Duration::from_millis(
+        config::RESUME_GRACE_PERIOD_MS(),
+    )));
+    let shutdown = utils::CancellationToken::new();
+    let auth_secret = config::AUTH_SECRET();
+    if auth_secret.is_none() {
+        warn!("AUTH_SECRET not set, clients will NOT be authenticated");
+    }
+    let rate_limit_counters = rate_limit::RateLimitCounters::default();
+    let backpressure_counters = backpressure::BackpressureCounters::default();
+    let metrics = Arc::new(metrics::Metrics::default());
+    let clock: Arc<dyn clock::Clock> = Arc::new(clock::SystemClock);
+    let shared_catalog = Arc::new(gameplay::SharedCatalog::new(&catalog));
+    let admin = admin::AdminState::new(admin::AuditTrail::open(
+        config::ADMIN_AUDIT_LOG_PATH().as_deref(),
+    )?);
+    let (socket, broadcaster) = server::set_up_websockets_server(server::ServerResources {
+        update_channel: &update_channel_tx,
+        resolver: &resolver,
+        sessions: &sessions,
+        shutdown: &shutdown,
+        auth_secret: auth_secret.as_ref().map(String::as_bytes),
+        rate_limit_counters: &rate_limit_counters,
+        backpressure_counters: &backpressure_counters,
+        admin: &admin,
+        metrics: &metrics,
+        clock: &*clock,
+        catalog: &shared_catalog,
+    });
 
     // Start update game_controller.
-    let map = geography::GameMap { max_dimension: 100 };
-    let game = game_control::GameController::new(update_channel_rx, broadcaster, map);
-    let terminate_fn = game_control::start_game_controller_thread(game)?;
+    let mut game = game_control::GameController::new(
+        update_channel_rx,
+        broadcaster.clone(),
+        map,
+        sessions.clone(),
+        metrics.clone(),
+        clock.clone(),
+    );
+    game.set_catalog(catalog);
+    game.share_catalog_with(shared_catalog.clone());
+    if let Some(path) = config::GAMEPLAY_PATH() {
+        info!(
+            "reloading the gameplay catalog when [{}] changes (or upon SIGHUP)",
+            path
+        );
+        let (reloads_tx, reloads_rx) = mpsc::channel();
+        game.reload_catalog_from(reloads_rx);
+        gameplay::watch(path, reloads_tx, shutdown.child_token())?;
+    }
+    if let Some(path) = config::RECORDING_PATH() {
+        info!("recording the match to [{}]", path);
+        game.record_to(recording::Recorder::create(
+            &path,
+            &game.recording_header(),
+        )?);
+    }
+    let game_controller = game_control::start_game_controller_thread(
+        game,
+        shutdown.child_token(),
+        update_channel_tx.clone(),
+    )?;
+
+    // Drain everything on SIGINT/SIGTERM (and SIGHUP, unless it reloads the gameplay catalog).
+    let termination_signals: Vec<_> = shutdown::TERMINATION_SIGNALS
+        .iter()
+        .copied()
+        .filter(|&signal| signal != signal_hook::SIGHUP || config::GAMEPLAY_PATH().is_none())
+        .collect();
+    shutdown::install_signal_handler(shutdown.clone(), broadcaster, &termination_signals)?;
 
     // Start listening (on event loop).
+    let mut exit_status = EXIT_OK;
     if let Err(error) = socket.listen(socket_address) {
-        error!("failed to create websocket due to {:?}", error)
+        error!("failed to create websocket due to {:?}", error);
+        exit_status = EXIT_SOCKET_FAILED;
     }
 
     // If the websockets server quit for some reason, terminate the update game_controller.
-    terminate_fn();
+    shutdown.cancel();
+    if game_controller.join().is_err() && exit_status == EXIT_OK {
+        exit_status = EXIT_GAME_CONTROLLER_FAILED;
+    }
 
-    info!("game server closed.");
-    Ok(())
+    info!("game server closed, exiting with status [{}].", exit_status);
+    std::process::exit(exit_status)
 }
 
 #[cfg(test)]
@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// scrape this (plain http, on the game port) for metrics in the Prometheus text format;
+/// when clients are authenticated (see `AUTH_SECRET`), scrapers need an admin token too
+pub const METRICS_PATH: &str = "/metrics";
+
+/// upper bounds (in seconds) of the tick duration histogram buckets
+const TICK_DURATION_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+/// upper bounds (in seconds) of the round trip time histogram buckets
+const RTT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Measurements gathered by the game controller and the server handlers.
+/// (other components keep their own counters, see `server::render_metrics`)
+#[derive(Debug)]
+pub struct Metrics {
+    pub tick_duration: Histogram,
+    pub rtt: Histogram,
+    /// by message type
+    pub messages_in: LabeledCounter,
+    /// by message type (GAME_STATE for snapshots)
+    pub messages_out: LabeledCounter,
+    pub bytes_out: AtomicU64,
+    pub players: AtomicU64,
+    pub spectators: AtomicU64,
+    pub enemies: AtomicU64,
+    pub projectiles: AtomicU64,
+    /// updates the game controller refused (e.g. from unknown players)
+    pub updates_rejected: AtomicU64,
+    pub controller_restarts: AtomicU64,
+    /// 1 while the game controller thread is running
+    pub controller_up: AtomicU64,
+    /// when the game controller last completed a tick (unix time, ms; 0 before the first)
+    pub last_tick_unix_ms: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            tick_duration: Histogram::new(TICK_DURATION_BUCKETS),
+            rtt: Histogram::new(RTT_BUCKETS),
+            messages_in: LabeledCounter::default(),
+            messages_out: LabeledCounter::default(),
+            bytes_out: AtomicU64::new(0),
+            players: AtomicU64::new(0),
+            spectators: AtomicU64::new(0),
+            enemies: AtomicU64::new(0),
+            projectiles: AtomicU64::new(0),
+            updates_rejected: AtomicU64::new(0),
+            controller_restarts: AtomicU64::new(0),
+            controller_up: AtomicU64::new(0),
+            last_tick_unix_ms: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn render(&self, out: &mut Exposition) {
+        out.histogram(
+            "tide_tick_duration_seconds",
+            "time the game controller spends per tick (besides waiting for updates)",
+            &self.tick_duration,
+        );
+        out.histogram(
+            "tide_rtt_seconds",
+            "round trip time of pings to players",
+            &self.rtt,
+        );
+        out.labeled_counter(
+            "tide_messages_in_total",
+            "messages received from players",
+            "type",
+            &self.messages_in,
+        );
+        out.labeled_counter(
+            "tide_messages_out_total",
+            "messages sent to clients",
+            "type",
+            &self.messages_out,
+        );
+        out.counter(
+            "tide_bytes_out_total",
+            "bytes sent to clients",
+            &self.bytes_out,
+        );
+        out.gauge("tide_players", "players in the game state", &self.players);
+        out.gauge(
+            "tide_spectators",
+            "open spectator connections",
+            &self.spectators,
+        );
+        out.gauge("tide_enemies", "enemies in the game state", &self.enemies);
+        out.gauge(
+            "tide_projectiles",
+            "projectiles in flight",
+            &self.projectiles,
+        );
+        out.counter(
+            "tide_updates_rejected_total",
+            "updates the game controller refused",
+            &self.updates_rejected,
+        );
+        out.counter(
+            "tide_controller_restarts_total",
+            "times the game controller was restarted after panicking",
+            &self.controller_restarts,
+        );
+        out.gauge(
+            "tide_controller_up",
+            "whether the game controller thread is running",
+            &self.controller_up,
+        );
+    }
+}
+
+/// cumulative buckets, as Prometheus expects
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// one per bound, plus +Inf
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(value.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// a counter per label value (e.g. per message type)
+#[derive(Debug, Default)]
+pub struct LabeledCounter {
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl LabeledCounter {
+    pub fn increment(&self, label: &str) {
+        let mut values = self.values.lock().unwrap();
+        match values.get_mut(label) {
+            Some(value) => *value += 1,
+            None => {
+                values.insert(label.to_owned(), 1);
+            }
+        }
+    }
+}
+
+/// Builds a response in the Prometheus text exposition format.
+#[derive(Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn counter(&mut self, name: &str, help: &str, value: &AtomicU64) {
+        self.header(name, help, "counter");
+        writeln!(self.text, "{} {}", name, value.load(Ordering::Relaxed)).unwrap();
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: &AtomicU64) {
+        self.header(name, help, "gauge");
+        writeln!(self.text, "{} {}", name, value.load(Ordering::Relaxed)).unwrap();
+    }
+
+    pub fn labeled_counter(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        counter: &LabeledCounter,
+    ) {
+        self.header(name, help, "counter");
+        for (value, count) in counter.values.lock().unwrap().iter() {
+            writeln!(self.text, "{}{{{}=\"{}\"}} {}", name, label, value, count).unwrap();
+        }
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "histogram");
+        let mut cumulative = 0;
+        for (i, bucket) in histogram.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = match histogram.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            writeln!(
+                self.text,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name, bound, cumulative
+            )
+            .unwrap();
+        }
+        let sum = histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        writeln!(self.text, "{}_sum {}", name, sum).unwrap();
+        writeln!(self.text, "{}_count {}", name, cumulative).unwrap();
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        writeln!(self.text, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.text, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+/// the "type" of a serialized `api::ServerUpdate` (e.g. `{"type":"ERROR",...}` -> "ERROR")
+pub fn server_update_type(json: &[u8]) -> Option<&str> {
+    let rest = json.strip_prefix(b"{\"type\":\"")?;
+    let end = rest.iter().position(|c| *c == b'"')?;
+    std::str::from_utf8(&rest[..end]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{server_update_type, Exposition, Histogram, LabeledCounter};
+
+    use std::time::Duration;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[0.01, 0.1]);
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_secs(1));
+
+        let mut out = Exposition::default();
+        out.histogram("latency_seconds", "test", &histogram);
+        let text = out.into_text();
+        assert!(text.contains("# TYPE latency_seconds histogram\n"));
+        assert!(text.contains("latency_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(text.contains("latency_seconds_bucket{le=\"0.1\"} 2\n"));
+        assert!(text.contains("latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("latency_seconds_sum 1.055\n"));
+        assert!(text.contains("latency_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn labeled_counters_and_message_types() {
+        let counter = LabeledCounter::default();
+        counter.increment("ERROR");
+        counter.increment("ERROR");
+        let mut out = Exposition::default();
+        out.labeled_counter("messages_total", "test", "type", &counter);
+        assert!(out
+            .into_text()
+            .contains("messages_total{type=\"ERROR\"} 2\n"));
+
+        assert_eq!(
+            server_update_type(br#"{"type":"ADMIN_RESULT","ok":true}"#),
+            Some("ADMIN_RESULT")
+        );
+        assert_eq!(server_update_type(br#"{"players":{}}"#), None);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::api_types as api;
+use crate::config;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// violations are forgotten after this long without any
+const VIOLATION_MEMORY: Duration = Duration::from_secs(10);
+
+/// Classic token bucket: holds up to `capacity` tokens (i.e. the allowed burst),
+/// refilled continuously at `refill_per_sec`, each message takes one.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(refill_per_sec: f64, capacity: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            refill_per_sec,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// what to do with a message
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// silently ignore it
+    Drop,
+    /// ignore it, and tell the client they're being throttled
+    Warn,
+    /// they're not slowing down, close the connection
+    Disconnect,
+}
+
+/// message categories, each with its own budget
+#[derive(Debug, Clone, Copy)]
+pub enum MessageKind {
+    PositionUpdate,
+    ProjectileCreated,
+    /// anything we couldn't parse
+    Invalid,
+}
+
+impl MessageKind {
+    /// (e.g. for metrics)
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageKind::PositionUpdate => "POSITION_UPDATE",
+            MessageKind::ProjectileCreated => "PROJECTILE_CREATED",
+            MessageKind::Invalid => "INVALID",
+        }
+    }
+
+    pub fn of(update: &api::ClientUpdate) -> MessageKind {
+        match update {
+            api::ClientUpdate::PositionUpdate(_) => MessageKind::PositionUpdate,
+            api::ClientUpdate::ProjectileCreated(_) => MessageKind::ProjectileCreated,
+            // never sent by clients
+            api::ClientUpdate::PlayerConnected(_)
+            | api::ClientUpdate::PlayerDisconnected(_)
+            | api::ClientUpdate::ConnectionQualityMeasured(_)
+            | api::ClientUpdate::Admin(_) => MessageKind::Invalid,
+        }
+    }
+}
+
+/// Per-connection limits: a token bucket per message kind, with escalating
+/// responses as violations pile up (drop, then warn, then disconnect).
+#[derive(Debug)]
+pub struct RateLimiter {
+    position_updates: TokenBucket,
+    projectiles: TokenBucket,
+    invalid: TokenBucket,
+    violations: u64,
+    last_violation: Option<Instant>,
+    warn_after: u64,
+    disconnect_after: u64,
+}
+
+impl RateLimiter {
+    pub fn from_config(now: Instant) -> RateLimiter {
+        let bucket = |per_sec: u64, burst: u64| TokenBucket::new(per_sec as f64, burst as f64, now);
+        RateLimiter {
+            position_updates: bucket(
+                config::RATE_LIMIT_POSITION_UPDATES_PER_SEC(),
+                config::RATE_LIMIT_POSITION_UPDATES_BURST(),
+            ),
+            projectiles: bucket(
+                config::RATE_LIMIT_PROJECTILES_PER_SEC(),
+                config::RATE_LIMIT_PROJECTILES_BURST(),
+            ),
+            invalid: bucket(
+                config::RATE_LIMIT_INVALID_PER_SEC(),
+                config::RATE_LIMIT_INVALID_BURST(),
+            ),
+            violations: 0,
+            last_violation: None,
+            warn_after: config::RATE_LIMIT_WARN_AFTER_VIOLATIONS(),
+            disconnect_after: config::RATE_LIMIT_DISCONNECT_AFTER_VIOLATIONS(),
+        }
+    }
+
+    pub fn check(&mut self, kind: MessageKind, now: Instant) -> Verdict {
+        let bucket = match kind {
+            MessageKind::PositionUpdate => &mut self.position_updates,
+            MessageKind::ProjectileCreated => &mut self.projectiles,
+            MessageKind::Invalid => &mut self.invalid,
+        };
+        if bucket.try_take(now) {
+            Verdict::Allow
+        } else {
+            self.violation(now)
+        }
+    }
+
+    /// registers a violation, escalating as they pile up
+    fn violation(&mut self, now: Instant) -> Verdict {
+        if let Some(last_violation) = self.last_violation {
+            if now.saturating_duration_since(last_violation) > VIOLATION_MEMORY {
+                self.violations = 0;
+            }
+        }
+        self.last_violation = Some(now);
+        self.violations += 1;
+        if self.violations >= self.disconnect_after {
+            Verdict::Disconnect
+        } else if self.violations == self.warn_after {
+            // once per escalation, so warnings can't become a flood of their own
+            Verdict::Warn
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+/// server-wide tallies of rate limiting verdicts
+#[derive(Debug, Default)]
+pub struct RateLimitCounters {
+    pub dropped: AtomicU64,
+    pub warned: AtomicU64,
+    pub disconnected: AtomicU64,
+    pub oversized: AtomicU64,
+}
+
+impl RateLimitCounters {
+    pub fn count(&self, verdict: &Verdict) {
+        let counter = match verdict {
+            Verdict::Allow => return,
+            Verdict::Drop => &self.dropped,
+            Verdict::Warn => &self.warned,
+            Verdict::Disconnect => &self.disconnected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MessageKind, RateLimiter, TokenBucket, Verdict};
+
+    use std::time::{Duration, Instant};
+
+    fn limiter(now: Instant) -> RateLimiter {
+        RateLimiter {
+            position_updates: TokenBucket::new(10.0, 2.0, now),
+            projectiles: TokenBucket::new(1.0, 1.0, now),
+            invalid: TokenBucket::new(1.0, 1.0, now),
+            violations: 0,
+            last_violation: None,
+            warn_after: 2,
+            disconnect_after: 4,
+        }
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2.0, start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+        assert!(bucket.try_take(start + Duration::from_millis(100)));
+        assert!(!bucket.try_take(start + Duration::from_millis(100)));
+        // never more than the burst
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.try_take(later));
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn kinds_have_separate_budgets() {
+        let now = Instant::now();
+        let mut limiter = limiter(now);
+        assert_eq!(
+            limiter.check(MessageKind::ProjectileCreated, now),
+            Verdict::Allow
+        );
+        assert_eq!(
+            limiter.check(MessageKind::PositionUpdate, now),
+            Verdict::Allow
+        );
+        assert_eq!(
+            limiter.check(MessageKind::ProjectileCreated, now),
+            Verdict::Drop
+        );
+        assert_eq!(
+            limiter.check(MessageKind::PositionUpdate, now),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn escalates_from_drop_to_warn_to_disconnect() {
+        let now = Instant::now();
+        let mut limiter = limiter(now);
+        assert_eq!(limiter.check(MessageKind::Invalid, now), Verdict::Allow);
+        assert_eq!(limiter.check(MessageKind::Invalid, now), Verdict::Drop);
+        assert_eq!(limiter.check(MessageKind::Invalid, now), Verdict::Warn);
+        assert_eq!(limiter.check(MessageKind::Invalid, now), Verdict::Drop);
+        assert_eq!(
+            limiter.check(MessageKind::Invalid, now),
+            Verdict::Disconnect
+        );
+    }
+
+    #[test]
+    fn violations_are_forgotten_after_a_while() {
+        let now = Instant::now();
+        let mut limiter = limiter(now);
+        assert_eq!(limiter.violation(now), Verdict::Drop);
+        let later = now + Duration::from_secs(60);
+        assert_eq!(limiter.violation(later), Verdict::Drop);
+        assert_eq!(limiter.violation(later), Verdict::Warn);
+    }
+}
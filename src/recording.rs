@@ -0,0 +1,360 @@
+use crate::api_types as api;
+use crate::config;
+use crate::game_control::GameController;
+use crate::intercomm::ChannelUpdate;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+/* Match recordings: everything the game controller consumed, tick by tick.
+
+One json object per line; the header first, then one line per tick:
+    {"version": 1, "seed": 8836..., "mapMaxDimension": 100, "tunables": [...],
+        "catalog": {"projectiles": {...}, ...}}
+    {"tick": 1, "nowMs": 1585098000050, "digest": "5f0e..."}
+    {"tick": 2, "nowMs": 1585098000100,
+        "updates": [{"playerId": 1, "update": {"PLAYER_CONNECTED": null}}],
+        "digest": "c01d..."}
+
+`nowMs` is the clock reading the tick was simulated at, and `digest` identifies
+the resulting game state, so a replay can tell exactly where it diverges. Every
+so often (`RECORDING_SNAPSHOT_INTERVAL_TICKS`) a tick also carries the full
+state, to diff against.
+
+The header also carries the gameplay catalog the match started with, and a tick
+after a reload carries the new one (see `gameplay`).
+
+Not captured: tunables changed mid-match (only their values at the start).
+*/
+
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Header {
+    pub version: u32,
+    /// seeds the game controller's randomness (e.g. where enemies spawn)
+    pub seed: u64,
+    pub map_max_dimension: usize,
+    pub tunables: Vec<(String, u64)>,
+    /// (missing from recordings made before there were catalog files, which used the built-in one)
+    #[serde(default)]
+    pub catalog: Option<api::Catalog>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TickRecord {
+    pub tick: u64,
+    pub now_ms: u64,
+    /// in the order they were applied
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub updates: Vec<RecordedUpdate>,
+    /// players whose session expired this tick
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evicted: Vec<api::PlayerId>,
+    /// when the gameplay catalog was reloaded just before this tick
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub catalog: Option<api::Catalog>,
+    pub digest: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedUpdate {
+    pub player_id: api::PlayerId,
+    pub update: Update,
+}
+
+/// `api::ClientUpdate`, including the variants clients can't send (and so can't be serialized there)
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Update {
+    PositionUpdate(api::PositionStamped),
+    ProjectileCreated(api::ProjectileSnaphot),
+    PlayerConnected(Option<api::Identity>),
+    PlayerDisconnected,
+    ConnectionQualityMeasured(api::ConnectionQuality),
+    Admin(api::AdminCommand),
+}
+
+impl From<&api::ClientUpdate> for Update {
+    fn from(update: &api::ClientUpdate) -> Update {
+        match update {
+            api::ClientUpdate::PositionUpdate(position) => Update::PositionUpdate(position.clone()),
+            api::ClientUpdate::ProjectileCreated(projectile) => {
+                Update::ProjectileCreated(projectile.clone())
+            }
+            api::ClientUpdate::PlayerConnected(identity) => {
+                Update::PlayerConnected(identity.clone())
+            }
+            api::ClientUpdate::PlayerDisconnected(()) => Update::PlayerDisconnected,
+            api::ClientUpdate::ConnectionQualityMeasured(quality) => {
+                Update::ConnectionQualityMeasured(quality.clone())
+            }
+            api::ClientUpdate::Admin(command) => Update::Admin(command.clone()),
+        }
+    }
+}
+
+impl From<Update> for api::ClientUpdate {
+    fn from(update: Update) -> api::ClientUpdate {
+        match update {
+            Update::PositionUpdate(position) => api::ClientUpdate::PositionUpdate(position),
+            Update::ProjectileCreated(projectile) => {
+                api::ClientUpdate::ProjectileCreated(projectile)
+            }
+            Update::PlayerConnected(identity) => api::ClientUpdate::PlayerConnected(identity),
+            Update::PlayerDisconnected => api::ClientUpdate::PlayerDisconnected(()),
+            Update::ConnectionQualityMeasured(quality) => {
+                api::ClientUpdate::ConnectionQualityMeasured(quality)
+            }
+            Update::Admin(command) => api::ClientUpdate::Admin(command),
+        }
+    }
+}
+
+impl TickRecord {
+    /// taken before the tick is applied (which consumes the updates)
+    pub fn new(
+        tick: u64,
+        now_ms: u64,
+        updates: &[ChannelUpdate],
+        evicted: &[api::PlayerId],
+    ) -> TickRecord {
+        TickRecord {
+            tick,
+            now_ms,
+            updates: updates
+                .iter()
+                .map(|ChannelUpdate { id, update }| RecordedUpdate {
+                    player_id: *id,
+                    update: update.into(),
+                })
+                .collect(),
+            evicted: evicted.to_vec(),
+            catalog: None,
+            digest: String::new(),
+            snapshot: None,
+        }
+    }
+}
+
+/// the game state as compared between runs (maps have their keys sorted, unlike `GameState`'s)
+pub fn canonical(state: &api::GameState) -> Value {
+    serde_json::to_value(state).unwrap()
+}
+
+pub fn digest(canonical_state: &Value) -> String {
+    let hash = Sha256::digest(canonical_state.to_string().as_bytes());
+    hex::encode(&hash[..8])
+}
+
+/// Writes the recording as the game controller goes.
+#[derive(Debug)]
+pub struct Recorder {
+    out: BufWriter<File>,
+    snapshot_interval_ticks: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &str, header: &Header) -> std::io::Result<Recorder> {
+        let mut recorder = Recorder {
+            out: BufWriter::new(File::create(path)?),
+            snapshot_interval_ticks: config::RECORDING_SNAPSHOT_INTERVAL_TICKS(),
+        };
+        recorder.write_line(&serde_json::ser::to_string(header).unwrap())?;
+        Ok(recorder)
+    }
+
+    /// completes the tick's record with the state it resulted in, and writes it
+    pub fn record(
+        &mut self,
+        mut record: TickRecord,
+        state: &api::GameState,
+    ) -> std::io::Result<()> {
+        let state = canonical(state);
+        record.digest = digest(&state);
+        if self.snapshot_interval_ticks > 0
+            && record.tick.is_multiple_of(self.snapshot_interval_ticks)
+        {
+            record.snapshot = Some(state);
+        }
+        self.write_line(&serde_json::ser::to_string(&record).unwrap())
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        writeln!(self.out, "{}", line)?;
+        // (so that a crash leaves everything up to it)
+        self.out.flush()
+    }
+}
+
+#[derive(Debug)]
+pub struct Recording {
+    pub header: Header,
+    pub ticks: Vec<TickRecord>,
+}
+
+pub fn read(path: &str) -> Result<Recording, String> {
+    let file = File::open(path).map_err(|error| format!("cannot open [{}]: {}", path, error))?;
+    let mut lines = BufReader::new(file).lines().enumerate();
+    let parse_error = |line_number: usize, error: &dyn std::fmt::Display| {
+        format!("[{}] line {}: {}", path, line_number + 1, error)
+    };
+    let header: Header = match lines.next() {
+        Some((i, line)) => {
+            let line = line.map_err(|error| parse_error(i, &error))?;
+            serde_json::from_str(&line).map_err(|error| parse_error(i, &error))?
+        }
+        None => return Err(format!("[{}] is empty", path)),
+    };
+    if header.version != VERSION {
+        return Err(format!(
+            "[{}] is a version {} recording, expected version {}",
+            path, header.version, VERSION
+        ));
+    }
+    let mut ticks = vec![];
+    for (i, line) in lines {
+        let line = line.map_err(|error| parse_error(i, &error))?;
+        ticks.push(serde_json::from_str(&line).map_err(|error| parse_error(i, &error))?);
+    }
+    Ok(Recording { header, ticks })
+}
+
+/// Feeds the recorded ticks back into `game` (set up as in the recording, see
+/// `GameController::replaying`), calling `on_tick` with each tick's record and
+/// the canonical state it resulted in.
+pub fn replay(
+    game: &mut GameController,
+    ticks: Vec<TickRecord>,
+    mut on_tick: impl FnMut(&TickRecord, &Value),
+) -> Result<(), String> {
+    for mut record in ticks {
+        let updates = std::mem::take(&mut record.updates)
+            .into_iter()
+            .map(|RecordedUpdate { player_id, update }| ChannelUpdate {
+                id: player_id,
+                update: update.into(),
+            })
+            .collect();
+        if let Some(catalog) = record.catalog.take() {
+            game.set_catalog(catalog);
+        }
+        game.apply_tick(record.now_ms, updates, &record.evicted)
+            .map_err(|details| format!("tick {} failed: [{}]", record.tick, details))?;
+        on_tick(&record, &canonical(game.state()));
+    }
+    Ok(())
+}
+
+/// where `replayed` differs from `original`, e.g. `players.1.health: 100 -> 90`
+pub fn diff(original: &Value, replayed: &Value) -> Vec<String> {
+    let mut differences = vec![];
+    diff_at("", original, replayed, &mut differences);
+    differences
+}
+
+fn diff_at(path: &str, original: &Value, replayed: &Value, differences: &mut Vec<String>) {
+    let child = |key: &str| {
+        if path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    match (original, replayed) {
+        (Value::Object(original), Value::Object(replayed)) => {
+            for (key, value) in original {
+                match replayed.get(key) {
+                    Some(replayed_value) => {
+                        diff_at(&child(key), value, replayed_value, differences)
+                    }
+                    None => differences.push(format!("{}: {} -> (missing)", child(key), value)),
+                }
+            }
+            for (key, value) in replayed {
+                if !original.contains_key(key) {
+                    differences.push(format!("{}: (missing) -> {}", child(key), value));
+                }
+            }
+        }
+        (Value::Array(original), Value::Array(replayed)) if original.len() == replayed.len() => {
+            for (i, (value, replayed_value)) in original.iter().zip(replayed).enumerate() {
+                diff_at(&child(&i.to_string()), value, replayed_value, differences);
+            }
+        }
+        _ if original != replayed => {
+            differences.push(format!("{}: {} -> {}", path, original, replayed))
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, read, replay, Recorder};
+    use crate::api_types as api;
+    use crate::game_control::GameController;
+    use crate::intercomm::ChannelUpdate;
+
+    use std::fs;
+
+    #[test]
+    fn replays_to_the_recorded_states() {
+        let path = std::env::temp_dir().join(format!("tide-recording-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut game = GameController::replaying(42, 100);
+        let mut recorder = Recorder::create(path, &game.recording_header()).unwrap();
+        let ticks: Vec<Vec<ChannelUpdate>> = vec![
+            vec![ChannelUpdate {
+                id: 1,
+                update: api::ClientUpdate::PlayerConnected(None),
+            }],
+            vec![ChannelUpdate {
+                id: 1,
+                update: api::ClientUpdate::Admin(api::AdminCommand::SpawnEnemies {
+                    count: 3,
+                    enemy_type: None,
+                }),
+            }],
+            vec![],
+        ];
+        for (i, updates) in ticks.into_iter().enumerate() {
+            let record = super::TickRecord::new(i as u64 + 1, 1_000 * i as u64, &updates, &[]);
+            game.apply_tick(record.now_ms, updates, &[]).unwrap();
+            recorder.record(record, game.state()).unwrap();
+        }
+
+        let recording = read(path).unwrap();
+        assert_eq!(recording.ticks.len(), 3);
+        let mut replayed_game = GameController::replaying(recording.header.seed, 100);
+        let mut digests = vec![];
+        replay(&mut replayed_game, recording.ticks, |record, state| {
+            digests.push((record.digest.clone(), super::digest(state)));
+        })
+        .unwrap();
+        assert!(digests
+            .iter()
+            .all(|(original, replayed)| original == replayed));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn diffs_name_the_differing_fields() {
+        let original = serde_json::json!({"players": {"1": {"health": 100}}, "enemies": []});
+        let replayed = serde_json::json!({"players": {"1": {"health": 90}}, "enemies": []});
+        assert_eq!(
+            diff(&original, &replayed),
+            vec!["players.1.health: 100 -> 90"]
+        );
+        assert!(diff(&original, &original).is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::api_types as api;
+use crate::utils;
+use crate::utils::SerialIdGenerator;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Decides which player a newly opened connection is.
+///
+/// Implementations must never hand out an id which is still in use by another
+/// open connection (i.e. not yet `release`d).
+pub trait PlayerIdResolver {
+    /// Err is why not
+    fn resolve(&self, shake: &ws::Handshake) -> Result<api::PlayerId, String>;
+
+    /// Marks an id obtained elsewhere (e.g. a resumed session) as in use.
+    /// Returns false if it's already in use.
+    fn claim(&self, id: api::PlayerId) -> bool;
+
+    /// the connection with this id closed
+    fn release(&self, id: api::PlayerId);
+}
+
+/// every connection is a new player (unless it claims an id, e.g. resuming a session)
+#[cfg_attr(feature = "ip-address-player-ids", allow(dead_code))]
+pub struct SequentialPlayerIdResolver {
+    player_id_gen: utils::PlayerIdGenerator,
+    in_use: Mutex<HashSet<api::PlayerId>>,
+}
+
+#[cfg_attr(feature = "ip-address-player-ids", allow(dead_code))]
+impl SequentialPlayerIdResolver {
+    pub fn new(first_id: api::PlayerId) -> SequentialPlayerIdResolver {
+        SequentialPlayerIdResolver {
+            player_id_gen: utils::PlayerIdGenerator::new(first_id),
+            in_use: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl PlayerIdResolver for SequentialPlayerIdResolver {
+    fn resolve(&self, _shake: &ws::Handshake) -> Result<api::PlayerId, String> {
+        let mut in_use = self.in_use.lock().unwrap();
+        // (skipping any claimed out of turn, e.g. by an auth token)
+        loop {
+            let id = self.player_id_gen.get_next_id();
+            if in_use.insert(id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    fn claim(&self, id: api::PlayerId) -> bool {
+        self.in_use.lock().unwrap().insert(id)
+    }
+
+    fn release(&self, id: api::PlayerId) {
+        self.in_use.lock().unwrap().remove(&id);
+    }
+}
+
+/// Each ip address maps to a stable player id, so reconnecting from the same
+/// address picks up the same player.
+///
+/// Several simultaneous connections from one address (e.g. behind a NAT, or a
+/// second browser tab) each get their own "slot" at that address, with its own
+/// stable id: a connection takes the lowest slot not currently in use.
+#[cfg_attr(not(feature = "ip-address-player-ids"), allow(dead_code))]
+pub struct IpAddressPlayerIdResolver {
+    player_id_gen: utils::PlayerIdGenerator,
+    state: Mutex<IpAddressResolverState>,
+}
+
+#[cfg_attr(not(feature = "ip-address-player-ids"), allow(dead_code))]
+#[derive(Default)]
+struct IpAddressResolverState {
+    /// address -> id of each slot, in slot order
+    ids_by_address: HashMap<String, Vec<api::PlayerId>>,
+    in_use: HashSet<api::PlayerId>,
+}
+
+#[cfg_attr(not(feature = "ip-address-player-ids"), allow(dead_code))]
+impl IpAddressPlayerIdResolver {
+    pub fn new(first_id: api::PlayerId) -> IpAddressPlayerIdResolver {
+        IpAddressPlayerIdResolver {
+            player_id_gen: utils::PlayerIdGenerator::new(first_id),
+            state: Mutex::new(IpAddressResolverState::default()),
+        }
+    }
+
+    pub fn resolve_address(&self, address: &str) -> api::PlayerId {
+        let mut state = self.state.lock().unwrap();
+        let IpAddressResolverState {
+            ids_by_address,
+            in_use,
+        } = &mut *state;
+        let slots = ids_by_address
+            .entry(address.to_owned())
+            .or_insert_with(Vec::new);
+        let id = match slots.iter().find(|id| !in_use.contains(id)) {
+            Some(id) => *id,
+            None => {
+                let id = self.player_id_gen.get_next_id();
+                slots.push(id);
+                id
+            }
+        };
+        in_use.insert(id);
+        id
+    }
+}
+
+impl PlayerIdResolver for IpAddressPlayerIdResolver {
+    fn resolve(&self, shake: &ws::Handshake) -> Result<api::PlayerId, String> {
+        match shake.remote_addr().map_err(|error| error.to_string())? {
+            Some(address) => Ok(self.resolve_address(&address)),
+            None => Err("client address unknown".to_owned()),
+        }
+    }
+
+    fn claim(&self, id: api::PlayerId) -> bool {
+        self.state.lock().unwrap().in_use.insert(id)
+    }
+
+    fn release(&self, id: api::PlayerId) {
+        self.state.lock().unwrap().in_use.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpAddressPlayerIdResolver, PlayerIdResolver, SequentialPlayerIdResolver};
+
+    #[test]
+    fn same_address_gets_same_id_back() {
+        let resolver = IpAddressPlayerIdResolver::new(1);
+        let first = resolver.resolve_address("10.0.0.1");
+        let other = resolver.resolve_address("10.0.0.2");
+        assert_ne!(first, other);
+
+        resolver.release(first);
+        assert_eq!(resolver.resolve_address("10.0.0.1"), first);
+    }
+
+    #[test]
+    fn simultaneous_connections_from_one_address_get_distinct_stable_ids() {
+        let resolver = IpAddressPlayerIdResolver::new(1);
+        let first = resolver.resolve_address("10.0.0.1");
+        let second = resolver.resolve_address("10.0.0.1");
+        assert_ne!(first, second);
+
+        resolver.release(second);
+        resolver.release(first);
+        assert_eq!(resolver.resolve_address("10.0.0.1"), first);
+        assert_eq!(resolver.resolve_address("10.0.0.1"), second);
+    }
+
+    #[test]
+    fn claimed_ids_are_not_handed_out() {
+        let resolver = IpAddressPlayerIdResolver::new(1);
+        let id = resolver.resolve_address("10.0.0.1");
+        assert!(!resolver.claim(id));
+
+        resolver.release(id);
+        assert!(resolver.claim(id));
+        assert_ne!(resolver.resolve_address("10.0.0.1"), id);
+    }
+
+    #[test]
+    fn sequential_ids_can_only_be_claimed_once_at_a_time() {
+        let resolver = SequentialPlayerIdResolver::new(1);
+        assert!(resolver.claim(42));
+        assert!(!resolver.claim(42));
+
+        resolver.release(42);
+        assert!(resolver.claim(42));
+    }
+}
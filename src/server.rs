@@ -1,105 +1,885 @@
+use crate::admin::{self, AdminState};
 use crate::api_types as api;
+use crate::auth;
+use crate::backpressure::{BackpressureCounters, OutboundMonitor, SnapshotVerdict};
+use crate::clock::Clock;
 use crate::config;
-use crate::intercomm::ChannelUpdate;
+use crate::gameplay::SharedCatalog;
+use crate::health;
+use crate::intercomm::{ChannelUpdate, InboxSender, SendError};
+use crate::latency::LatencyTracker;
+use crate::metrics::{self, Exposition, Metrics};
+use crate::rate_limit::{MessageKind, RateLimitCounters, RateLimiter, Verdict};
+use crate::resolver::PlayerIdResolver;
+use crate::sessions::SessionRegistry;
 use crate::utils;
-use crate::utils::SerialIdGenerator;
 
 use mio_extras::timer::Timeout;
 use ws;
 use ws::util::Token;
 
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// what every connection shares
+#[derive(Clone, Copy)]
+pub struct ServerResources<'a> {
+    pub update_channel: &'a InboxSender,
+    pub resolver: &'a dyn PlayerIdResolver,
+    pub sessions: &'a SessionRegistry,
+    pub shutdown: &'a utils::CancellationToken,
+    /// if None, clients aren't authenticated
+    pub auth_secret: Option<&'a [u8]>,
+    pub rate_limit_counters: &'a RateLimitCounters,
+    pub backpressure_counters: &'a BackpressureCounters,
+    pub admin: &'a AdminState,
+    pub metrics: &'a Metrics,
+    /// for the ping logic (heartbeats, round trip times, slow clients)
+    pub clock: &'a dyn Clock,
+    /// sent to players and spectators upon connecting
+    pub catalog: &'a SharedCatalog,
+}
 
 pub fn set_up_websockets_server<'a>(
-    update_channel_tx: &'a mpsc::Sender<ChannelUpdate>,
-    player_id_gen: &'a utils::PlayerIdGenerator,
+    resources: ServerResources<'a>,
 ) -> (ws::WebSocket<ServerFactory<'a>>, ws::Sender) {
-    let server_factory = ServerFactory {
-        update_channel: &update_channel_tx,
-        player_id_gen: player_id_gen,
-    };
-    let socket = ws::Builder::new().build(server_factory).unwrap();
+    let server_factory = ServerFactory { resources };
+    let socket = ws::Builder::new()
+        .with_settings(ws::Settings {
+            // (frames are checked by ws, whole messages in `on_message`)
+            max_fragment_size: config::MAX_MESSAGE_BYTES(),
+            max_connections: config::WS_MAX_CONNECTIONS(),
+            queue_size: config::WS_QUEUE_SIZE(),
+            ..ws::Settings::default()
+        })
+        .build(server_factory)
+        .unwrap();
     let broadcaster = socket.broadcaster();
     (socket, broadcaster)
 }
 
+/// Drains the websockets server, meant to run once `shutdown` is cancelled
+/// (by which point new connections are already being turned away):
+///   1. tell every client why we're going away
+///   2. close every connection, and give clients time to finish the close handshake
+///   3. stop the event loop (i.e. `WebSocket::listen` returns)
+pub fn drain_websockets_server(broadcaster: &ws::Sender, reason: &str) {
+    let shutdown_msg =
+        serde_json::ser::to_string(&api::ServerUpdate::ServerShutdown(api::ServerShutdownMessage {
+            reason: reason.to_owned(),
+        }))
+        .unwrap();
+    if let Err(error) = broadcaster.broadcast(shutdown_msg) {
+        warn!("failed to broadcast shutdown message: [{:?}]", error);
+    }
+
+    if let Err(error) = broadcaster.close_with_reason(SHUTDOWN_CLOSE_CODE, reason.to_owned()) {
+        warn!("failed to close client connections: [{:?}]", error);
+    }
+    thread::sleep(Duration::from_millis(config::SHUTDOWN_DRAIN_MS()));
+
+    if let Err(error) = broadcaster.shutdown() {
+        error!("failed to stop websockets event loop: [{:?}]", error);
+    }
+}
+
+/// everything gathered by the game controller and the server handlers, in the Prometheus text format
+fn render_metrics(resources: &ServerResources) -> String {
+    let mut out = Exposition::default();
+    resources.metrics.render(&mut out);
+    out.gauge(
+        "tide_connected_players",
+        "open player connections",
+        &AtomicU64::new(resources.admin.connection_count() as u64),
+    );
+    let inbox = resources.update_channel.metrics();
+    out.gauge(
+        "tide_inbox_queued",
+        "updates awaiting the game controller",
+        &inbox.queued,
+    );
+    out.gauge(
+        "tide_inbox_queued_high_water",
+        "most updates ever awaiting the game controller",
+        &inbox.queued_high_water,
+    );
+    out.counter(
+        "tide_inbox_rejected_total",
+        "updates refused because the game controller was falling behind",
+        &inbox.rejected,
+    );
+    out.counter(
+        "tide_inbox_positions_coalesced_total",
+        "position updates superseded before being applied",
+        &inbox.positions_coalesced,
+    );
+    let rate_limits = resources.rate_limit_counters;
+    out.counter(
+        "tide_rate_limit_dropped_total",
+        "messages dropped by rate limiting",
+        &rate_limits.dropped,
+    );
+    out.counter(
+        "tide_rate_limit_warned_total",
+        "clients warned by rate limiting",
+        &rate_limits.warned,
+    );
+    out.counter(
+        "tide_rate_limit_disconnected_total",
+        "clients disconnected by rate limiting",
+        &rate_limits.disconnected,
+    );
+    out.counter(
+        "tide_oversized_messages_total",
+        "messages rejected for their size",
+        &rate_limits.oversized,
+    );
+    let backpressure = resources.backpressure_counters;
+    out.counter(
+        "tide_snapshots_coalesced_total",
+        "snapshots superseded before reaching a slow client",
+        &backpressure.snapshots_coalesced,
+    );
+    out.counter(
+        "tide_slow_clients_disconnected_total",
+        "clients disconnected for not keeping up",
+        &backpressure.slow_clients_disconnected,
+    );
+    out.into_text()
+}
+
+/// what the health and readiness probes are judged on, as of now
+fn observe_health(resources: &ServerResources) -> health::Observations {
+    let metrics = resources.metrics;
+    let last_tick_unix_ms = metrics.last_tick_unix_ms.load(Ordering::Relaxed);
+    let now_unix_ms = utils::unix_time().as_millis() as u64;
+    health::Observations {
+        controller_alive: metrics.controller_up.load(Ordering::Relaxed) == 1,
+        last_tick_age: if last_tick_unix_ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(
+                now_unix_ms.saturating_sub(last_tick_unix_ms),
+            ))
+        },
+        max_tick_age: Duration::from_millis(config::HEALTH_MAX_TICK_AGE_MS()),
+        connections: resources.admin.connection_count(),
+        capacity: config::MAX_PLAYERS(),
+        shutting_down: resources.shutdown.is_cancelled(),
+    }
+}
+
 const PING: Token = Token(1);
 
+/// i.e. 1001, "going away"
+const SHUTDOWN_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Away;
+
+/// the connection stopped answering pings (application-defined range 4000-4999)
+const HEARTBEAT_TIMEOUT_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Other(4000);
+
+/// the client isn't reading game state as fast as it's sent
+const SLOW_CLIENT_CLOSE_CODE: ws::CloseCode = ws::CloseCode::Other(4001);
+
+pub const RESUME_TOKEN_QUERY_PARAM: &str = "resumeToken";
+
+/// connect here to watch the game without playing (as does anyone authenticated as a SPECTATOR)
+pub const SPECTATE_PATH: &str = "/spectate";
+
 // websockets game server
 pub struct GameServer<'a> {
     out: ws::Sender,
-    update_channel: mpsc::Sender<ChannelUpdate>,
+    update_channel: InboxSender,
     player_id: Option<api::PlayerId>,
     ping_timeout: Option<Timeout>,
-    player_id_gen: &'a utils::PlayerIdGenerator,
+    resources: ServerResources<'a>,
+    /// given by the client at handshake, if reconnecting
+    resume_token: Option<String>,
+    /// verified at handshake (None if the server doesn't authenticate clients)
+    identity: Option<api::Identity>,
+    rate_limiter: RateLimiter,
+    latency: LatencyTracker,
+    /// (clock reading)
+    last_pong: Duration,
+    outbound: OutboundMonitor,
+    /// the newest game state snapshot, held back while the client catches up
+    pending_snapshot: Option<ws::Frame>,
+    /// the player was disconnected from the game (the socket may not be closed yet)
+    session_ended: bool,
+    /// connected to the admin endpoint, rather than playing
+    is_admin: bool,
+    /// watching the game, rather than playing
+    is_spectator: bool,
+    /// the player whose viewpoint the spectator is watching from
+    following: Option<api::PlayerId>,
+    /// (see `AdminState::reserve_player_slot`), from the handshake until the session ends
+    holds_player_slot: bool,
 }
 
 impl<'a> GameServer<'a> {
-    fn new(
-        out: ws::Sender,
-        update_channel: mpsc::Sender<ChannelUpdate>,
-        player_id_gen: &'a utils::PlayerIdGenerator,
-    ) -> GameServer<'_> {
+    fn new(out: ws::Sender, resources: ServerResources<'a>) -> GameServer<'a> {
         GameServer {
-            out: out,
-            update_channel: update_channel,
+            out,
+            update_channel: resources.update_channel.clone(),
             player_id: None,
             ping_timeout: None,
-            player_id_gen: player_id_gen,
+            resources,
+            resume_token: None,
+            identity: None,
+            rate_limiter: RateLimiter::from_config(Instant::now()),
+            latency: LatencyTracker::new(config::LATENCY_WINDOW_SAMPLES()),
+            last_pong: resources.clock.now(),
+            outbound: OutboundMonitor::from_config(),
+            pending_snapshot: None,
+            session_ended: false,
+            is_admin: false,
+            is_spectator: false,
+            following: None,
+            holds_player_slot: false,
+        }
+    }
+
+    /// Resumes the client's previous session if possible, otherwise starts a new one.
+    /// Err is the reason the client may not play.
+    fn start_session(&mut self, shake: &ws::Handshake) -> Result<(api::PlayerId, String), String> {
+        let resources = self.resources;
+        let authenticated_id = self.identity.as_ref().map(|identity| identity.player_id);
+        if let Some(resume_token) = self.resume_token.take() {
+            // (the token is only spent once the id is ours)
+            match resources.sessions.resumable(&resume_token) {
+                Some(id) if authenticated_id.is_some_and(|auth_id| auth_id != id) => {
+                    warn!("player [{}] tried resuming someone else's session", &id)
+                }
+                Some(id) if resources.resolver.claim(id) => {
+                    match resources.sessions.resume(&resume_token) {
+                        Some((id, new_resume_token)) => {
+                            info!("player [{}] resumed their session", &id);
+                            return Ok((id, new_resume_token));
+                        }
+                        None => {
+                            resources.resolver.release(id);
+                            warn!(
+                                "session of player [{}] ended meanwhile, starting a new one",
+                                &id
+                            )
+                        }
+                    }
+                }
+                Some(id) => warn!(
+                    "player [{}] is already connected, starting a new session",
+                    &id
+                ),
+                None => warn!("rejected resume token, starting a new session"),
+            }
         }
+        let id: api::PlayerId = match authenticated_id {
+            Some(id) if resources.resolver.claim(id) => id,
+            Some(id) => return Err(format!("player [{}] is already connected", id)),
+            None => resources
+                .resolver
+                .resolve(shake)
+                .map_err(|error| format!("cannot assign player id: {}", error))?,
+        };
+        Ok((id, resources.sessions.issue(id)))
     }
 }
 
-impl<'a> GameServer<'_> {
+// (the helpers return what the handlers do, i.e. the large `ws::Error`)
+#[allow(clippy::result_large_err)]
+impl GameServer<'_> {
+    /// Verifies the request's token, if the server authenticates clients; Err is the
+    /// response refusing it.
+    fn authenticate(&mut self, req: &ws::Request) -> Result<(), ws::Response> {
+        let secret = match self.resources.auth_secret {
+            Some(secret) => secret,
+            None => return Ok(()),
+        };
+        let now_secs = utils::unix_time().as_secs();
+        let verified = auth::token_from_request(req)
+            .ok_or(auth::AuthError::MissingToken)
+            .and_then(|token| auth::verify_token(secret, &token, now_secs));
+        match verified {
+            Ok(identity) => {
+                info!(
+                    "authenticated player [{}] as [{}] ({:?})",
+                    identity.player_id, identity.display_name, identity.role
+                );
+                self.identity = Some(identity);
+                Ok(())
+            }
+            Err(error) => {
+                warn!("rejecting connection: {}", error);
+                let mut response =
+                    ws::Response::new(401, "Unauthorized", error.to_string().into_bytes());
+                response
+                    .headers_mut()
+                    .push(("WWW-Authenticate".to_owned(), b"Bearer".to_vec()));
+                Err(response)
+            }
+        }
+    }
+
+    fn authenticated_as(&self, role: api::Role) -> bool {
+        match &self.identity {
+            Some(identity) => identity.role == role,
+            None => false,
+        }
+    }
+
+    fn release_player_slot(&mut self) {
+        if self.holds_player_slot {
+            self.holds_player_slot = false;
+            self.resources.admin.release_player_slot();
+        }
+    }
+
+    /// who's on the other end, for logs
+    fn peer(&self) -> String {
+        let connection_id = self.out.connection_id();
+        match (&self.player_id, &self.identity) {
+            (Some(id), _) => format!("player [{}]", id),
+            (None, Some(identity)) if self.is_admin => {
+                format!("admin [{}] ({})", identity.player_id, identity.display_name)
+            }
+            (None, _) if self.is_spectator => format!("spectator [{}]", connection_id),
+            // (refused, e.g. banned)
+            (None, _) => format!("connection [{}]", connection_id),
+        }
+    }
+
     fn send_out(&mut self, data: String) -> ws::Result<()> {
         debug!("server sending: [{}]", data);
         self.out.send(data)
     }
 
     fn send_ping(&mut self) -> ws::Result<()> {
-        self.out.ping(utils::custom_time_ns().to_string().into())
+        let now = self.resources.clock.custom_time_ns();
+        self.latency.ping_sent();
+        self.outbound.ping_sent(now);
+        self.out.ping(now.to_string().into())
     }
-}
 
-impl<'a> ws::Handler for GameServer<'_> {
-    fn on_open(&mut self, shake: ws::Handshake) -> ws::Result<()> {
-        let id: api::PlayerId = self.player_id_gen.get_next_id();
+    /// Whether the client has missed too many pings in a row, e.g. a half-open TCP connection.
+    fn heartbeat_lost(&self, now: Duration) -> bool {
+        let allowed = Duration::from_millis(config::WEBSOCKETS_PINGPONG_INTERVAL_MS())
+            * config::HEARTBEAT_MISSED_INTERVALS();
+        now.checked_sub(self.last_pong).unwrap_or_default() > allowed
+    }
+
+    /// Disconnects the player from the game, at most once per connection.
+    ///
+    /// Happens upon close, or as soon as the connection is deemed dead: closing a
+    /// half-open connection may take a long while, which the player shouldn't
+    /// spend lingering in the game.
+    fn end_session(&mut self) {
+        self.release_player_slot();
+        // Rejected during handshake, never became a player.
+        if self.player_id.is_none() || self.session_ended {
+            return;
+        }
+        self.session_ended = true;
+        self.resources.admin.disconnected(self.player_id.unwrap());
+        // Let them resume within the grace period.
+        self.resources.sessions.hold(self.player_id.unwrap());
+        self.resources.resolver.release(self.player_id.unwrap());
+        self.forward(api::ClientUpdate::PlayerDisconnected(()));
+
+        // Inform all existing clients of this player being disconnected.
+        // (they'll remain in the game state as DISCONNECTED until their session expires)
+        let player_disconnected_msg = serde_json::ser::to_string(
+            &api::ServerUpdate::PlayerDisconnected(api::PlayerIdMessage {
+                player_id: self.player_id.unwrap(),
+            }),
+        )
+        .unwrap();
+        if let Err(error) = self.out.broadcast(player_disconnected_msg) {
+            warn!("failed to broadcast player disconnection: [{:?}]", error);
+        }
+    }
+
+    /// puts the (newly resolved) player in the game, and tells them who they are
+    fn welcome_player(
+        &mut self,
+        shake: &ws::Handshake,
+        ip: String,
+        resume_token: String,
+    ) -> ws::Result<()> {
+        let id = self.player_id.unwrap();
         info!("Connection with player [{}] now open, with ip addresses = {{ local=[{}], peer=[{}], remote=[{}] }}", &id, shake.local_addr.unwrap(), shake.peer_addr.unwrap(), shake.remote_addr()?.unwrap());
 
         // setup time sync timeouts
-        self.player_id = Some(id.clone());
         self.send_ping()?;
         self.out
             .timeout(config::WEBSOCKETS_PINGPONG_INTERVAL_MS(), PING)?;
-        self.update_channel
-            .send(ChannelUpdate {
-                id: id.clone(),
-                update: api::ClientUpdate::PlayerConnected(()),
-            })
-            .unwrap();
+        self.forward(api::ClientUpdate::PlayerConnected(self.identity.clone()));
+        self.resources
+            .admin
+            .connected(id, ip, self.out.clone(), self.identity.clone());
+        // (before the id, so that clients know their weapons by the time they play)
+        self.send_out(self.resources.catalog.message())?;
         let player_id_assignment_msg =
-            serde_json::ser::to_string(&api::ServerUpdate::YourPlayerId(api::PlayerIdMessage {
-                player_id: id,
-            }))
+            serde_json::ser::to_string(&api::ServerUpdate::YourPlayerId(
+                api::YourPlayerIdMessage {
+                    player_id: id,
+                    resume_token,
+                },
+            ))
             .unwrap();
         self.out.send(player_id_assignment_msg)
     }
 
+    /// Passes an update on to the game controller, on behalf of this player.
+    /// If the controller can't keep up, the update is dropped (see `intercomm::inbox`).
+    fn forward(&self, update: api::ClientUpdate) {
+        let id = self.player_id.unwrap();
+        match self.update_channel.send(ChannelUpdate { id, update }) {
+            Ok(()) => {}
+            Err(SendError::Full) => {
+                let metrics = self.update_channel.metrics();
+                debug!(
+                    "game controller falling behind, dropped update from player [{}] ({} dropped so far, up to {} queued)",
+                    id,
+                    metrics.rejected.load(Ordering::Relaxed),
+                    metrics.queued_high_water.load(Ordering::Relaxed)
+                );
+            }
+            // (the game controller is already gone if we're shutting down)
+            Err(SendError::Closed) => warn!(
+                "game controller not listening, dropped update from player [{}]",
+                id
+            ),
+        }
+    }
+
+    /// runs a command from an admin, recording it to the audit trail
+    fn handle_admin_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let command: api::AdminCommand = match serde_json::from_str(msg.as_text()?) {
+            Ok(command) => command,
+            Err(error) => {
+                return self.send_admin_result(Err(format!("unrecognized command: [{}]", error)))
+            }
+        };
+        let admin = self.resources.admin;
+        let outcome = match &command {
+            api::AdminCommand::ListPlayers => {
+                let players = admin.players();
+                let player_list_msg = serde_json::ser::to_string(&api::ServerUpdate::PlayerList(
+                    api::PlayerListMessage { players },
+                ))
+                .unwrap();
+                self.send_out(player_list_msg)?;
+                Ok("listed players".to_owned())
+            }
+            api::AdminCommand::Kick { player_id, reason } => admin
+                .kick(
+                    *player_id,
+                    reason.as_deref().unwrap_or("kicked by an admin"),
+                )
+                .map(|()| format!("kicked player [{}]", player_id)),
+            api::AdminCommand::Ban { player_id, reason } => admin
+                .ban(
+                    *player_id,
+                    reason.as_deref().unwrap_or("banned by an admin"),
+                )
+                .map(|ip| format!("banned player [{}] at [{}]", player_id, ip)),
+            api::AdminCommand::Mute { player_id } => admin
+                .set_muted(*player_id, true)
+                .map(|()| format!("muted player [{}]", player_id)),
+            api::AdminCommand::Unmute { player_id } => admin
+                .set_muted(*player_id, false)
+                .map(|()| format!("unmuted player [{}]", player_id)),
+            api::AdminCommand::SpawnEnemies { count, .. }
+                if *count > config::ADMIN_MAX_SPAWN_ENEMIES() =>
+            {
+                Err(format!(
+                    "can't spawn more than {} enemies at once",
+                    config::ADMIN_MAX_SPAWN_ENEMIES()
+                ))
+            }
+            api::AdminCommand::SpawnEnemies {
+                enemy_type: Some(enemy_type),
+                ..
+            } if !self.resources.catalog.has_enemy_type(enemy_type) => {
+                Err(format!("no such enemy type [{}]", enemy_type))
+            }
+            api::AdminCommand::PauseMatch
+            | api::AdminCommand::ResumeMatch
+            | api::AdminCommand::SpawnEnemies { .. } => self
+                .update_channel
+                .send(ChannelUpdate {
+                    id: self.identity.as_ref().unwrap().player_id,
+                    update: api::ClientUpdate::Admin(command.clone()),
+                })
+                .map(|()| "passed on to the game controller".to_owned())
+                .map_err(|error| error.to_string()),
+            api::AdminCommand::SetTunable { name, value } => {
+                config::set_tunables(&[(name.as_str(), *value)])
+                    .map(|()| format!("{} = {}", name, value))
+            }
+            api::AdminCommand::Announce { message } => {
+                let announcement_msg = serde_json::ser::to_string(
+                    &api::ServerUpdate::Announcement(api::AnnouncementMessage {
+                        message: message.clone(),
+                    }),
+                )
+                .unwrap();
+                self.out
+                    .broadcast(announcement_msg)
+                    .map(|()| "announced".to_owned())
+                    .map_err(|error| error.to_string())
+            }
+        };
+        admin
+            .audit()
+            .record(self.identity.as_ref().unwrap(), &command, &outcome);
+        match command {
+            api::AdminCommand::ListPlayers if outcome.is_ok() => Ok(()),
+            _ => self.send_admin_result(outcome),
+        }
+    }
+
+    /// Spectators may only pick whom to follow; gameplay messages are refused.
+    /// (they only send the odd FOLLOW, so everything they send comes out of the smallest budget)
+    fn handle_spectator_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        if self.session_ended || !self.admit(MessageKind::Invalid)? {
+            return Ok(());
+        }
+        let json = msg.as_text()?;
+        if let Ok(api::SpectatorCommand::Follow { player_id }) = serde_json::from_str(json) {
+            if let Some(id) = player_id {
+                if !self.resources.admin.is_connected(id) {
+                    return self.send_error(
+                        api::ErrorCode::UnknownPlayer,
+                        format!("player [{}] is not connected", id),
+                    );
+                }
+            }
+            self.following = player_id;
+            return self.send_spectating();
+        }
+        match serde_json::from_str::<api::ClientUpdate>(json) {
+            Ok(_) => self.send_error(
+                api::ErrorCode::SpectatorsCannotPlay,
+                "spectators can only FOLLOW players".to_owned(),
+            ),
+            Err(error) => {
+                let error_msg = format!(
+                    "unrecognized message from {}: [{}], error: [{:?}]",
+                    self.peer(),
+                    json,
+                    error
+                );
+                warn!("{}", error_msg);
+                self.send_out(error_msg)
+            }
+        }
+    }
+
+    fn send_spectating(&mut self) -> ws::Result<()> {
+        let spectating_msg =
+            serde_json::ser::to_string(&api::ServerUpdate::Spectating(api::SpectatingMessage {
+                spectator_id: self.out.connection_id(),
+                following: self.following,
+            }))
+            .unwrap();
+        self.send_out(spectating_msg)
+    }
+
+    fn send_error(&mut self, code: api::ErrorCode, message: String) -> ws::Result<()> {
+        let error_msg = serde_json::ser::to_string(&api::ServerUpdate::Error(api::ErrorMessage {
+            code,
+            message,
+        }))
+        .unwrap();
+        self.send_out(error_msg)
+    }
+
+    fn send_admin_result(&mut self, outcome: Result<String, String>) -> ws::Result<()> {
+        let (ok, message) = match outcome {
+            Ok(message) => (true, message),
+            Err(message) => (false, message),
+        };
+        let admin_result_msg =
+            serde_json::ser::to_string(&api::ServerUpdate::AdminResult(api::AdminResultMessage {
+                ok,
+                message,
+            }))
+            .unwrap();
+        self.send_out(admin_result_msg)
+    }
+
+    /// Holds back game state snapshots from clients which can't keep up (see `OutboundMonitor`).
+    /// None means the frame mustn't be sent (yet).
+    fn hold_back_snapshots(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        // Only game state snapshots may be held back: each supersedes the previous,
+        // whereas other messages would be lost.
+        let is_snapshot = frame.opcode() == ws::OpCode::Text
+            && frame
+                .payload()
+                .starts_with(api::GAME_STATE_JSON_PREFIX.as_bytes());
+        if is_snapshot && self.is_admin {
+            return Ok(None);
+        }
+        if !is_snapshot || self.session_ended {
+            return Ok(Some(frame));
+        }
+        match self
+            .outbound
+            .snapshot_verdict(self.resources.clock.custom_time_ns())
+        {
+            SnapshotVerdict::Send => Ok(Some(frame)),
+            SnapshotVerdict::Coalesce => {
+                if self.pending_snapshot.replace(frame).is_some() {
+                    self.resources
+                        .backpressure_counters
+                        .snapshots_coalesced
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(None)
+            }
+            SnapshotVerdict::Disconnect => {
+                warn!(
+                    "{} can't keep up with game state updates, disconnecting them",
+                    self.peer()
+                );
+                self.resources
+                    .backpressure_counters
+                    .slow_clients_disconnected
+                    .fetch_add(1, Ordering::Relaxed);
+                self.pending_snapshot = None;
+                self.end_session();
+                self.out
+                    .close_with_reason(SLOW_CLIENT_CLOSE_CODE, "too slow reading game state")?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// sends the held back snapshot, if the client has caught up
+    fn flush_pending_snapshot(&mut self, now_ns: u64) -> ws::Result<()> {
+        if self.outbound.snapshot_verdict(now_ns) != SnapshotVerdict::Send {
+            return Ok(());
+        }
+        match self.pending_snapshot.take() {
+            Some(frame) => {
+                let snapshot = std::str::from_utf8(frame.payload())?.to_owned();
+                self.out.send(snapshot)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// shares the latest connection quality with the client, admins and the game controller
+    fn report_connection_quality(&mut self) -> ws::Result<()> {
+        let (id, quality) = match (self.player_id, self.latency.quality()) {
+            (Some(id), Some(quality)) => (id, quality),
+            _ => return Ok(()),
+        };
+        self.resources.admin.measured(id, quality.clone());
+        self.forward(api::ClientUpdate::ConnectionQualityMeasured(
+            quality.clone(),
+        ));
+        let quality_msg = serde_json::ser::to_string(&api::ServerUpdate::ConnectionQuality(
+            api::ConnectionQualityMessage {
+                player_id: id,
+                quality,
+            },
+        ))
+        .unwrap();
+        self.send_out(quality_msg)
+    }
+
+    /// Applies rate limiting to an incoming message, escalating as needed.
+    /// Ok(false) means the message must be dropped.
+    fn admit(&mut self, kind: MessageKind) -> ws::Result<bool> {
+        self.resources.metrics.messages_in.increment(kind.name());
+        let verdict = self.rate_limiter.check(kind, Instant::now());
+        self.resources.rate_limit_counters.count(&verdict);
+        match verdict {
+            Verdict::Allow => Ok(true),
+            Verdict::Drop => Ok(false),
+            Verdict::Warn => {
+                warn!("{} is sending {:?} messages too quickly", self.peer(), kind);
+                self.send_error(
+                    api::ErrorCode::RateLimited,
+                    "sending too quickly, messages are being dropped".to_owned(),
+                )?;
+                Ok(false)
+            }
+            Verdict::Disconnect => {
+                warn!(
+                    "{} kept exceeding rate limits, disconnecting them",
+                    self.peer()
+                );
+                self.out
+                    .close_with_reason(ws::CloseCode::Policy, "rate limit exceeded")?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl ws::Handler for GameServer<'_> {
+    fn on_request(&mut self, req: &ws::Request) -> ws::Result<ws::Response> {
+        // (plain http, for scrapers and load balancers)
+        let path = utils::resource_path(req.resource());
+        if path == health::HEALTH_PATH {
+            let observations = observe_health(&self.resources);
+            return Ok(health::response(&observations, observations.healthy()));
+        }
+        if path == health::READY_PATH {
+            let observations = observe_health(&self.resources);
+            return Ok(health::response(&observations, observations.ready()));
+        }
+        if path == metrics::METRICS_PATH {
+            // (admins only, when clients are authenticated: scrapers present an admin token)
+            if let Err(refusal) = self.authenticate(req) {
+                return Ok(refusal);
+            }
+            if self.resources.auth_secret.is_some() && !self.authenticated_as(api::Role::Admin) {
+                warn!("refusing metrics, not authenticated as an admin");
+                return Ok(ws::Response::new(403, "Forbidden", b"admins only".to_vec()));
+            }
+            let mut response =
+                ws::Response::new(200, "OK", render_metrics(&self.resources).into_bytes());
+            response.headers_mut().push((
+                "Content-Type".to_owned(),
+                b"text/plain; version=0.0.4".to_vec(),
+            ));
+            return Ok(response);
+        }
+        if self.resources.shutdown.is_cancelled() {
+            info!("rejecting connection, server is shutting down");
+            return Ok(ws::Response::new(
+                503,
+                "Service Unavailable",
+                b"server is shutting down".to_vec(),
+            ));
+        }
+        if let Err(refusal) = self.authenticate(req) {
+            return Ok(refusal);
+        }
+        if path == admin::ADMIN_PATH {
+            if !self.authenticated_as(api::Role::Admin) {
+                warn!("rejecting admin connection, not authenticated as an admin");
+                return Ok(ws::Response::new(403, "Forbidden", b"admins only".to_vec()));
+            }
+            self.is_admin = true;
+        }
+        let spectator_role = self.authenticated_as(api::Role::Spectator);
+        if path == SPECTATE_PATH || (spectator_role && !self.is_admin) {
+            self.is_spectator = true;
+        }
+        let is_player = !self.is_admin && !self.is_spectator;
+        if is_player {
+            // (taken right away, as connections accepted meanwhile aren't players yet)
+            let admin = self.resources.admin;
+            if !admin.reserve_player_slot(config::MAX_PLAYERS()) {
+                info!("rejecting connection, the server is full");
+                return Ok(ws::Response::new(
+                    503,
+                    "Service Unavailable",
+                    b"server is full".to_vec(),
+                ));
+            }
+            self.holds_player_slot = true;
+        }
+        self.resume_token =
+            utils::query_param(req.resource(), RESUME_TOKEN_QUERY_PARAM).map(str::to_owned);
+        ws::Response::from_request(req)
+    }
+
+    fn on_open(&mut self, shake: ws::Handshake) -> ws::Result<()> {
+        if self.is_admin {
+            info!(
+                "admin [{}] connected",
+                self.identity.as_ref().unwrap().display_name
+            );
+            return Ok(());
+        }
+        let ip = shake.remote_addr()?.unwrap_or_else(|| "unknown".to_owned());
+        if self.resources.admin.is_banned(&ip) {
+            warn!("refusing banned address [{}]", ip);
+            return self
+                .out
+                .close_with_reason(admin::KICKED_CLOSE_CODE, "banned");
+        }
+        if self.is_spectator {
+            info!("{} connected from [{}]", self.peer(), ip);
+            self.resources
+                .metrics
+                .spectators
+                .fetch_add(1, Ordering::Relaxed);
+            // (pinged like players, to spot dead connections and slow readers)
+            self.send_ping()?;
+            self.out
+                .timeout(config::WEBSOCKETS_PINGPONG_INTERVAL_MS(), PING)?;
+            self.send_out(self.resources.catalog.message())?;
+            return self.send_spectating();
+        }
+        let (id, resume_token) = match self.start_session(&shake) {
+            Ok(session) => session,
+            Err(reason) => {
+                warn!("refusing player: {}", reason);
+                return self.out.close_with_reason(ws::CloseCode::Policy, reason);
+            }
+        };
+        // (from here on, the id is given back however the connection ends)
+        self.player_id = Some(id);
+        let welcomed = self.welcome_player(&shake, ip, resume_token);
+        if welcomed.is_err() {
+            self.end_session();
+        }
+        welcomed
+    }
+
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        // (before anything else looks at it, not least the logs)
+        if msg.len() > config::MAX_MESSAGE_BYTES() {
+            self.resources
+                .rate_limit_counters
+                .oversized
+                .fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "{} sent a message of {} bytes, disconnecting them",
+                self.peer(),
+                msg.len()
+            );
+            return self
+                .out
+                .close_with_reason(ws::CloseCode::Size, "message too large");
+        }
+        if self.is_admin {
+            return self.handle_admin_message(msg);
+        }
+        if self.is_spectator {
+            return self.handle_spectator_message(msg);
+        }
+        // (e.g. a connection deemed dead which turned out not to be, or refused)
+        if self.session_ended || self.player_id.is_none() {
+            return Ok(());
+        }
         debug!(
             "server got from player [{}]: [{}]",
             self.player_id.as_ref().unwrap(),
             msg
         );
-        let id = self.player_id.as_ref().unwrap().clone();
+        let id = self.player_id.unwrap();
+        if self.resources.admin.is_muted(id) {
+            return Ok(());
+        }
         match msg {
             ws::Message::Text(json) => match serde_json::from_str(&json) {
                 Ok(update) => {
-                    self.update_channel
-                        .send(ChannelUpdate { id, update })
-                        .unwrap();
+                    if !self.admit(MessageKind::of(&update))? {
+                        return Ok(());
+                    }
+                    self.forward(update);
                 }
                 Err(error) => {
+                    if !self.admit(MessageKind::Invalid)? {
+                        return Ok(());
+                    }
                     let error_msg = format!(
                         "unrecognized message from player [{}]: [{}], error: [{:?}]",
                         self.player_id.as_ref().unwrap(),
@@ -124,9 +904,20 @@ impl<'a> ws::Handler for GameServer<'_> {
         match event {
             // PING timeout has occured, send a ping and reschedule
             PING => {
-                self.send_ping()?;
                 self.ping_timeout.take();
-                self.out.timeout(5_000, PING)
+                if self.heartbeat_lost(self.resources.clock.now()) {
+                    warn!(
+                        "{} stopped answering pings, closing their connection",
+                        self.peer()
+                    );
+                    self.end_session();
+                    return self
+                        .out
+                        .close_with_reason(HEARTBEAT_TIMEOUT_CLOSE_CODE, "heartbeat timeout");
+                }
+                self.send_ping()?;
+                self.out
+                    .timeout(config::WEBSOCKETS_PINGPONG_INTERVAL_MS(), PING)
             }
             // No other timeouts are possible
             _ => Err(ws::Error::new(
@@ -158,16 +949,29 @@ impl<'a> ws::Handler for GameServer<'_> {
     }
 
     fn on_frame(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
-        // If the frame is a pong, print the round-trip time.
+        // If the frame is a pong, record the round-trip time.
         // The pong should contain data from out ping, but it isn't guaranteed to.
-        if frame.opcode() == ws::OpCode::Pong {
-            if let Ok(pong) = std::str::from_utf8(frame.payload())?.parse::<u64>() {
-                let now = utils::custom_time_ns();
-                debug!(
-                    "round trip time for player [{}] = {}ms",
-                    self.player_id.as_ref().unwrap(),
-                    (now - pong) as f32 / 1_000_000f32
-                );
+        if frame.opcode() == ws::OpCode::Pong && !self.session_ended {
+            self.last_pong = self.resources.clock.now();
+            let now = self.resources.clock.custom_time_ns();
+            let ping = std::str::from_utf8(frame.payload())?.parse::<u64>().ok();
+            if let Some(ping) = ping {
+                self.outbound.pong_received(ping);
+                self.flush_pending_snapshot(now)?;
+            }
+            let rtt_ns = ping.and_then(|ping| now.checked_sub(ping));
+            if let Some(rtt_ns) = rtt_ns {
+                let rtt_ms = rtt_ns as f64 / 1_000_000f64;
+                debug!("round trip time for {} = {}ms", self.peer(), rtt_ms);
+                self.latency.pong_received(rtt_ms);
+                self.resources
+                    .metrics
+                    .rtt
+                    .observe(Duration::from_nanos(rtt_ns));
+                // (spectators, admins and refused players aren't in the game)
+                if self.player_id.is_some() {
+                    self.report_connection_quality()?;
+                }
             } else {
                 warn!("received bad pong");
             }
@@ -177,29 +981,48 @@ impl<'a> ws::Handler for GameServer<'_> {
         DefaultHandler.on_frame(frame)
     }
 
+    fn on_send_frame(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        let frame = self.hold_back_snapshots(frame)?;
+        if let Some(frame) = &frame {
+            let metrics = self.resources.metrics;
+            metrics
+                .bytes_out
+                .fetch_add(frame.payload().len() as u64, Ordering::Relaxed);
+            if frame.opcode() == ws::OpCode::Text {
+                let payload = frame.payload();
+                if payload.starts_with(api::GAME_STATE_JSON_PREFIX.as_bytes()) {
+                    metrics.messages_out.increment("GAME_STATE");
+                } else if let Some(update_type) = metrics::server_update_type(payload) {
+                    metrics.messages_out.increment(update_type);
+                } else {
+                    metrics.messages_out.increment("OTHER");
+                }
+            }
+        }
+        Ok(frame)
+    }
+
     fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
         info!("WebSocket closing for [{:?}], reason: [{}]", code, reason);
         // Clean up time sync timeout.
         if let Some(t) = self.ping_timeout.take() {
             self.out.cancel(t).unwrap();
         }
-        // Close ipc channel.
-        self.update_channel
-            .send(ChannelUpdate {
-                id: self.player_id.as_ref().unwrap().clone(),
-                update: api::ClientUpdate::PlayerDisconnected(()),
-            })
-            .unwrap();
+        if self.is_spectator && !self.session_ended {
+            self.session_ended = true;
+            self.resources
+                .metrics
+                .spectators
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+        self.end_session();
+    }
+}
 
-        // Inform all existing clients of this player being disconnected.
-        // TODO: can we build in reconnection?
-        let player_disconnected_msg = serde_json::ser::to_string(
-            &api::ServerUpdate::PlayerDisconnected(api::PlayerIdMessage {
-                player_id: self.player_id.as_ref().unwrap().clone(),
-            }),
-        )
-        .unwrap();
-        self.out.broadcast(player_disconnected_msg).unwrap();
+impl<'a> Drop for GameServer<'a> {
+    /// (e.g. the handshake failed after a player slot was reserved for it)
+    fn drop(&mut self) {
+        self.release_player_slot();
     }
 }
 
@@ -209,8 +1032,7 @@ struct DefaultHandler;
 impl ws::Handler for DefaultHandler {}
 
 pub struct ServerFactory<'a> {
-    update_channel: &'a mpsc::Sender<ChannelUpdate>,
-    player_id_gen: &'a utils::PlayerIdGenerator,
+    resources: ServerResources<'a>,
 }
 
 impl<'a> ws::Factory for ServerFactory<'a> {
@@ -220,6 +1042,6 @@ impl<'a> ws::Factory for ServerFactory<'a> {
             "connected with client, connection id=[{}]",
             sender.connection_id()
         );
-        GameServer::new(sender, self.update_channel.clone(), self.player_id_gen)
+        GameServer::new(sender, self.resources)
     }
 }
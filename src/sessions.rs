@@ -0,0 +1,203 @@
+use crate::api_types as api;
+use crate::utils;
+
+use rand::Rng;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Tracks player sessions so that a client which drops its connection can
+/// come back as the same player, within a grace period.
+///
+/// Upon connecting, each player is issued a signed resume token
+/// (see `api::YourPlayerIdMessage`). When the connection closes, the session
+/// is held for `grace_period`, during which the player stays in the game
+/// (as `DISCONNECTED`). Reconnecting with the latest token resumes the
+/// session (and issues a new token, so each token is good for one resume).
+///
+/// This is the single authority on whether a session may still be resumed:
+/// the game controller only evicts a disconnected player once `expire`
+/// agrees, so a resume and an eviction can never both succeed.
+pub struct SessionRegistry {
+    /// per-process; sessions don't outlive the game state anyway
+    secret: [u8; 32],
+    grace_period: Duration,
+    sessions: Mutex<HashMap<api::PlayerId, Session>>,
+}
+
+impl std::fmt::Debug for SessionRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionRegistry")
+            .field("grace_period", &self.grace_period)
+            .field("sessions", &self.sessions)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    /// identifies the latest token issued to this player
+    nonce: u64,
+    disconnected_at: Option<Instant>,
+}
+
+impl SessionRegistry {
+    pub fn new(grace_period: Duration) -> SessionRegistry {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill(&mut secret);
+        SessionRegistry {
+            secret,
+            grace_period,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// starts (or restarts) the player's session, returning its resume token
+    pub fn issue(&self, id: api::PlayerId) -> String {
+        let nonce: u64 = rand::thread_rng().gen();
+        self.sessions.lock().unwrap().insert(
+            id,
+            Session {
+                nonce,
+                disconnected_at: None,
+            },
+        );
+        self.make_token(id, nonce)
+    }
+
+    /// the player's connection closed; their session may be resumed until the grace period elapses
+    pub fn hold(&self, id: api::PlayerId) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.disconnected_at = Some(Instant::now());
+        }
+    }
+
+    /// the player the token would resume (see `resume`), without spending it
+    pub fn resumable(&self, token: &str) -> Option<api::PlayerId> {
+        let (id, nonce) = self.parse_token(token)?;
+        let sessions = self.sessions.lock().unwrap();
+        if self.can_resume(sessions.get(&id)?, nonce) {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// If the token is genuine, the latest one issued for its player, and the
+    /// player is disconnected within the grace period, returns that player's
+    /// id along with a fresh resume token.
+    pub fn resume(&self, token: &str) -> Option<(api::PlayerId, String)> {
+        let (id, nonce) = self.parse_token(token)?;
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(&id)?;
+        if !self.can_resume(session, nonce) {
+            return None;
+        }
+        let new_nonce: u64 = rand::thread_rng().gen();
+        session.nonce = new_nonce;
+        session.disconnected_at = None;
+        Some((id, self.make_token(id, new_nonce)))
+    }
+
+    /// Ends the session if the player has been disconnected past the grace period,
+    /// in which case the player should be removed from the game.
+    pub fn expire(&self, id: api::PlayerId) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let expired = match sessions.get(&id) {
+            Some(Session {
+                disconnected_at: Some(disconnected_at),
+                ..
+            }) => disconnected_at.elapsed() > self.grace_period,
+            Some(Session {
+                disconnected_at: None,
+                ..
+            }) => false,
+            // nothing to resume
+            None => true,
+        };
+        if expired {
+            sessions.remove(&id);
+        }
+        expired
+    }
+
+    fn can_resume(&self, session: &Session, nonce: u64) -> bool {
+        match session.disconnected_at {
+            Some(disconnected_at) => {
+                session.nonce == nonce && disconnected_at.elapsed() <= self.grace_period
+            }
+            None => false,
+        }
+    }
+
+    /// token format: "<player id>.<nonce>.<signature>"
+    fn make_token(&self, id: api::PlayerId, nonce: u64) -> String {
+        let payload = format!("{}.{}", id, nonce);
+        let signature = utils::sign(&self.secret, payload.as_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    fn parse_token(&self, token: &str) -> Option<(api::PlayerId, u64)> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        if !utils::verify_signature(&self.secret, payload.as_bytes(), signature) {
+            return None;
+        }
+        let (id, nonce) = payload.split_once('.')?;
+        Some((id.parse().ok()?, nonce.parse().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionRegistry;
+
+    use std::time::Duration;
+
+    #[test]
+    fn resumes_disconnected_player_once() {
+        let sessions = SessionRegistry::new(Duration::from_secs(60));
+        let token = sessions.issue(7);
+
+        // still connected: nothing to resume
+        assert!(sessions.resume(&token).is_none());
+
+        sessions.hold(7);
+        // (checking doesn't spend it)
+        assert_eq!(sessions.resumable(&token), Some(7));
+        let (id, new_token) = sessions.resume(&token).unwrap();
+        assert_eq!(id, 7);
+
+        // the old token is spent
+        sessions.hold(7);
+        assert!(sessions.resumable(&token).is_none());
+        assert!(sessions.resume(&token).is_none());
+        assert!(sessions.resume(&new_token).is_some());
+    }
+
+    #[test]
+    fn rejects_forged_tokens() {
+        let sessions = SessionRegistry::new(Duration::from_secs(60));
+        let token = sessions.issue(7);
+        sessions.hold(7);
+        sessions.issue(8);
+        sessions.hold(8);
+
+        let forged = token.replacen("7.", "8.", 1);
+        assert!(sessions.resume(&forged).is_none());
+        assert!(sessions.resume("garbage").is_none());
+        assert!(sessions.resume("").is_none());
+    }
+
+    #[test]
+    fn expires_after_grace_period() {
+        let sessions = SessionRegistry::new(Duration::from_millis(0));
+        let token = sessions.issue(7);
+        assert!(!sessions.expire(7));
+
+        sessions.hold(7);
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(sessions.expire(7));
+        assert!(sessions.resume(&token).is_none());
+    }
+}
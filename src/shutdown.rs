@@ -0,0 +1,47 @@
+use crate::server;
+use crate::utils;
+
+use signal_hook::iterator::Signals;
+
+use std::os::raw::c_int;
+use std::thread;
+
+/// the signals which shut the server down, unless SIGHUP is needed for something
+/// else (e.g. reloading the gameplay catalog, see `gameplay::watch`)
+pub const TERMINATION_SIGNALS: [c_int; 3] = [
+    signal_hook::SIGINT,
+    signal_hook::SIGTERM,
+    signal_hook::SIGHUP,
+];
+
+/// Installs the handler for `signals` (see `TERMINATION_SIGNALS`), which cancels
+/// `shutdown`, then drains the websockets server (see
+/// `server::drain_websockets_server`), after which `WebSocket::listen` returns
+/// in the main thread.
+///
+/// Cancelling `shutdown` turns away new connections and stops everything
+/// holding a child token (e.g. the game controller), so anything else that
+/// should happen upon shutdown can be registered with `shutdown.on_cancel`.
+///
+/// A second signal during the drain is ignored.
+pub fn install_signal_handler(
+    shutdown: utils::CancellationToken,
+    broadcaster: ws::Sender,
+    signals: &[c_int],
+) -> std::io::Result<()> {
+    let signals = Signals::new(signals)?;
+    thread::Builder::new()
+        .name("SignalHandler".to_owned())
+        .spawn(move || {
+            for _ in signals.forever() {
+                if shutdown.is_cancelled() {
+                    warn!("already shutting down, ignoring signal");
+                    continue;
+                }
+                info!("received termination signal, shutting down...");
+                shutdown.cancel();
+                server::drain_websockets_server(&broadcaster, "server received termination signal");
+            }
+        })?;
+    Ok(())
+}
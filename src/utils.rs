@@ -1,10 +1,14 @@
 use crate::api_types as api;
 
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::{Condvar, Weak};
 use std::time::Duration;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 
 pub fn unix_time() -> Duration {
     SystemTime::now()
@@ -30,21 +34,179 @@ pub fn custom_time_ns() -> custom_nanos_t {
 }
 
 /// usage:
-///     let (cancelled, cancel) = make_atomic_canceller();
+///     let cancellation = CancellationToken::new();
+///     let worker_cancellation = cancellation.child_token();
 ///     thread::spawn(move || {
-///       while !cancelled() { /* ... */ }
+///       while !worker_cancellation.wait_timeout(Duration::from_secs(1)) { /* ... */ }
 ///       println!("cancel() called");
 ///     });
+///     cancellation.on_cancel(|| println!("shutting down"));
 ///     // ...
-///     cancel();
+///     cancellation.cancel();
 ///
-/// Note: make sure Ordering suits your needs (below)
-pub fn make_atomic_canceller() -> (impl Fn() -> bool, impl Fn() -> ()) {
-    let cancel_flag = Arc::new(AtomicBool::new(false));
-    let cancel_flag_receiver = cancel_flag.clone();
-    let cancelled = move || cancel_flag_receiver.load(Ordering::Relaxed);
-    let cancel = move || cancel_flag.store(true, Ordering::Relaxed);
-    (cancelled, cancel)
+/// Cancelling wakes every thread blocked in `wait`/`wait_timeout` right away,
+/// cancels every child token, then runs the registered callbacks (on the
+/// cancelling thread, in registration order). Callbacks registered after
+/// cancellation run immediately. Cancelling a child doesn't affect its parent.
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<CancellationInner>,
+}
+
+struct CancellationInner {
+    cancelled: AtomicBool,
+    state: Mutex<CancellationState>,
+    cancelled_cond: Condvar,
+}
+
+#[derive(Default)]
+struct CancellationState {
+    callbacks: Vec<Box<dyn FnOnce() + Send>>,
+    children: Vec<Weak<CancellationInner>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken {
+            inner: Arc::new(CancellationInner {
+                cancelled: AtomicBool::new(false),
+                state: Mutex::new(CancellationState::default()),
+                cancelled_cond: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    pub fn cancel(&self) {
+        CancellationToken::cancel_inner(&self.inner);
+    }
+
+    fn cancel_inner(inner: &CancellationInner) {
+        let (callbacks, children) = {
+            let mut state = inner.state.lock().unwrap();
+            if inner.cancelled.swap(true, Ordering::AcqRel) {
+                return;
+            }
+            inner.cancelled_cond.notify_all();
+            (
+                std::mem::take(&mut state.callbacks),
+                std::mem::take(&mut state.children),
+            )
+        };
+        for child in children.iter().filter_map(Weak::upgrade) {
+            CancellationToken::cancel_inner(&child);
+        }
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    /// a token which is cancelled along with this one (but may also be cancelled on its own)
+    pub fn child_token(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if !self.is_cancelled() {
+                state.children.retain(|child| child.strong_count() > 0);
+                state.children.push(Arc::downgrade(&child.inner));
+                return child;
+            }
+        }
+        child.cancel();
+        child
+    }
+
+    pub fn on_cancel<F: FnOnce() + Send + 'static>(&self, callback: F) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if !self.is_cancelled() {
+                state.callbacks.push(Box::new(callback));
+                return;
+            }
+        }
+        callback();
+    }
+
+    /// blocks until cancelled
+    pub fn wait(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        while !self.is_cancelled() {
+            state = self.inner.cancelled_cond.wait(state).unwrap();
+        }
+    }
+
+    /// blocks until cancelled, or until the timeout elapses; returns whether cancelled
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.inner.state.lock().unwrap();
+        while !self.is_cancelled() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .inner
+                .cancelled_cond
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> CancellationToken {
+        CancellationToken::new()
+    }
+}
+
+impl std::fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// e.g. "/admin?token=abc" -> "/admin"
+pub fn resource_path(resource: &str) -> &str {
+    match resource.split_once('?') {
+        Some((path, _)) => path,
+        None => resource,
+    }
+}
+
+/// e.g. ("/?resumeToken=abc&x=y", "resumeToken") -> Some("abc")
+pub fn query_param<'r>(resource: &'r str, name: &str) -> Option<&'r str> {
+    let (_, query) = resource.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|pair| match pair.split_once('=') {
+            Some((key, value)) if key == name => Some(value),
+            _ => None,
+        })
+}
+
+/// hex-encoded HMAC-SHA256 of `payload`
+pub fn sign(secret: &[u8], payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any length");
+    mac.input(payload);
+    hex::encode(mac.result().code())
+}
+
+/// constant-time check of a signature made by `sign`
+pub fn verify_signature(secret: &[u8], payload: &[u8], signature: &str) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any length");
+    mac.input(payload);
+    mac.verify(&signature).is_ok()
 }
 
 pub trait SerialIdGenerator {
@@ -57,17 +219,19 @@ pub trait SerialIdGenerator {
             panic!("overflow of ids, id=[{}], MASK=[{}]", id, Self::MASK);
         }
         *data += 1;
-        return id | Self::MASK;
+        id | Self::MASK
     }
     fn new(first_id: api::EntityId) -> Self;
     fn get_next_id_mutex(&self) -> &Mutex<api::EntityId>;
 }
 
 /// u32 with first two bits: 00
+#[derive(Debug)]
 pub struct PlayerIdGenerator {
     next_id: Mutex<api::EntityId>,
 }
 /// u32 with first two bits: 01
+#[derive(Debug)]
 pub struct EnemyIdGenerator {
     next_id: Mutex<api::EntityId>,
 }
@@ -75,6 +239,7 @@ pub struct EnemyIdGenerator {
 ///
 /// At 1,000 projectiles/seconds, this gives us:
 /// 3^31 / (1,000 projectiles/second) ~= 20,000 years of ids
+#[derive(Debug)]
 pub struct ProjectileIdGenerator {
     next_id: Mutex<api::EntityId>,
 }
@@ -112,3 +277,75 @@ impl SerialIdGenerator for ProjectileIdGenerator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CancellationToken;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn cancel_wakes_waiters_immediately() {
+        let cancellation = CancellationToken::new();
+        let waiter = {
+            let cancellation = cancellation.clone();
+            thread::spawn(move || cancellation.wait_timeout(Duration::from_secs(60)))
+        };
+        thread::sleep(Duration::from_millis(10));
+        let started = Instant::now();
+        cancellation.cancel();
+        assert!(waiter.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn wait_timeout_elapses_when_not_cancelled() {
+        let cancellation = CancellationToken::new();
+        assert!(!cancellation.wait_timeout(Duration::from_millis(1)));
+        assert!(!cancellation.is_cancelled());
+    }
+
+    #[test]
+    fn children_follow_parent_but_not_vice_versa() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+        let sibling = parent.child_token();
+
+        sibling.cancel();
+        assert!(!parent.is_cancelled());
+        assert!(!child.is_cancelled());
+
+        parent.cancel();
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+        assert!(parent.child_token().is_cancelled());
+    }
+
+    #[test]
+    fn callbacks_run_exactly_once() {
+        let cancellation = CancellationToken::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        {
+            let calls = calls.clone();
+            cancellation.on_cancel(move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        cancellation.cancel();
+        cancellation.cancel();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // registered after the fact: runs right away
+        {
+            let calls = calls.clone();
+            cancellation.on_cancel(move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
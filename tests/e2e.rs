@@ -0,0 +1,272 @@
+//! End-to-end: real clients against the whole server (see `harness`).
+
+mod harness;
+
+use harness::{wait_for, wait_for_state, TestServer};
+use tide::api_types as api;
+use tide::client::{ClientError, Event};
+use tide::metrics::METRICS_PATH;
+
+use std::time::Duration;
+
+#[test]
+fn players_are_assigned_ids_upon_connecting() {
+    let server = TestServer::start();
+    let mut alice = server.connect();
+    let bob = server.connect();
+    let (alice_id, bob_id) = (alice.player_id().unwrap(), bob.player_id().unwrap());
+    assert_ne!(alice_id, bob_id);
+    assert!(alice.resume_token().is_some());
+
+    wait_for_state(&mut alice, "both players in the game", |state| {
+        [alice_id, bob_id].iter().all(|id| {
+            matches!(
+                state.players.get(id).map(|player| &player.connection_status),
+                Some(api::ConnectionStatus::Connected)
+            )
+        })
+    });
+}
+
+#[test]
+fn a_token_only_plays_on_one_connection_at_a_time() {
+    let server = TestServer::start_authenticating();
+    let token = server.token(api::Role::Player);
+    let mut alice = server.connect_with_token(&token, "").unwrap();
+    let alice_id = alice.player_id().unwrap();
+
+    match server.connect_with_token(&token, "") {
+        Err(ClientError::Closed { code, reason }) => {
+            assert_eq!(code, ws::CloseCode::Policy);
+            assert!(reason.contains("already connected"), "{}", reason);
+        }
+        other => panic!("a second connection as alice: {:?}", other),
+    }
+    // (the first one plays on)
+    alice.send_position(api::Vec2::new(3.0, 4.0)).unwrap();
+    wait_for_state(&mut alice, "alice's new position", |state| {
+        state.players[&alice_id].position.xy == api::Vec2::new(3.0, 4.0)
+    });
+    alice.close();
+    let alice_again = server.connect_with_token(&token, "").unwrap();
+    assert_eq!(alice_again.player_id(), Some(alice_id));
+}
+
+#[test]
+fn clients_get_the_catalog_upon_connecting() {
+    let server = TestServer::start();
+    let alice = server.connect();
+    let spectator = server.spectate();
+    let catalog = tide::gameplay::built_in();
+    assert_eq!(alice.catalog(), Some(&catalog));
+    assert_eq!(spectator.catalog(), Some(&catalog));
+}
+
+#[test]
+fn disconnections_are_broadcast() {
+    let server = TestServer::start();
+    let mut alice = server.connect();
+    let bob = server.connect();
+    let bob_id = bob.player_id().unwrap();
+    bob.close();
+
+    let disconnected = wait_for(&mut alice, "bob's disconnection", |event| match event {
+        Event::Update(api::ServerUpdate::PlayerDisconnected(message)) => Some(message.player_id),
+        _ => None,
+    });
+    assert_eq!(disconnected, bob_id);
+    wait_for_state(&mut alice, "bob shown as disconnected", |state| {
+        matches!(
+            state.players.get(&bob_id).map(|player| &player.connection_status),
+            Some(api::ConnectionStatus::Disconnected)
+        )
+    });
+}
+
+#[test]
+fn malformed_messages_are_answered_without_disconnecting() {
+    let server = TestServer::start();
+    let mut alice = server.connect();
+    let alice_id = alice.player_id().unwrap();
+    alice.send_text("{\"type\": \"TELEPORT\"}").unwrap();
+
+    let complaint = wait_for(&mut alice, "the complaint", |event| match event {
+        Event::Unrecognized(text) => Some(text),
+        _ => None,
+    });
+    assert!(complaint.contains("unrecognized message"));
+    alice.send_position(api::Vec2::new(3.0, 4.0)).unwrap();
+    wait_for_state(&mut alice, "alice's new position", |state| {
+        state.players[&alice_id].position.xy == api::Vec2::new(3.0, 4.0)
+    });
+}
+
+#[test]
+fn projectiles_hit_enemies() {
+    let server = TestServer::start_authenticating();
+    let mut admin = server.connect_as(api::Role::Admin, tide::admin::ADMIN_PATH);
+    let mut alice = server.connect();
+    let alice_id = alice.player_id().unwrap();
+    admin
+        .admin(&api::AdminCommand::SpawnEnemies {
+            count: 1,
+            enemy_type: None,
+        })
+        .unwrap();
+    let result = wait_for(&mut admin, "the enemies to spawn", |event| match event {
+        Event::Update(api::ServerUpdate::AdminResult(result)) => Some(result),
+        _ => None,
+    });
+    assert!(result.ok, "{}", result.message);
+
+    let state = wait_for_state(&mut alice, "an enemy", |state| state.enemies.len() == 1);
+    let enemy = &state.enemies[0];
+    alice
+        .fire_projectile(
+            tide::gameplay::HIT_SCAN0,
+            enemy.position.xy - api::Vec2::new(5.0, 0.0),
+            api::Vec2::new(1.0, 0.0),
+        )
+        .unwrap();
+    let state = wait_for_state(&mut alice, "the hit", |state| {
+        state.players[&alice_id].stats.enemies_hit == 1
+    });
+    assert!(state.enemies[0].health < enemy.health);
+    assert_eq!(state.players[&alice_id].stats.shots_fired, 1);
+}
+
+#[test]
+fn spawning_too_many_or_unknown_enemies_is_refused() {
+    let server = TestServer::start_authenticating();
+    let mut admin = server.connect_as(api::Role::Admin, tide::admin::ADMIN_PATH);
+    let mut spawn = |count, enemy_type: Option<&str>| {
+        admin
+            .admin(&api::AdminCommand::SpawnEnemies {
+                count,
+                enemy_type: enemy_type.map(str::to_owned),
+            })
+            .unwrap();
+        wait_for(&mut admin, "the spawn result", |event| match event {
+            Event::Update(api::ServerUpdate::AdminResult(result)) => Some(result),
+            _ => None,
+        })
+    };
+    let result = spawn(u32::MAX, None);
+    assert!(!result.ok);
+    assert!(
+        result.message.contains("can't spawn more than"),
+        "{}",
+        result.message
+    );
+    let result = spawn(1, Some("DRAGON"));
+    assert!(!result.ok);
+    assert!(
+        result.message.contains("no such enemy type"),
+        "{}",
+        result.message
+    );
+    assert!(spawn(1, Some(tide::gameplay::GRUNT)).ok);
+}
+
+#[test]
+fn admins_may_answer_pings() {
+    let server = TestServer::start_authenticating();
+    let mut admin = server.connect_as(api::Role::Admin, tide::admin::ADMIN_PATH);
+    // (a timestamp long past, i.e. a round trip time to report)
+    admin.send_pong("1").unwrap();
+    admin.admin(&api::AdminCommand::ListPlayers).unwrap();
+    wait_for(&mut admin, "the player list", |event| match event {
+        Event::Update(api::ServerUpdate::PlayerList(_)) => Some(()),
+        _ => None,
+    });
+}
+
+#[test]
+fn metrics_are_for_admins_only() {
+    let server = TestServer::start_authenticating();
+    let metrics = |token: Option<String>| server.get(METRICS_PATH, token.as_deref());
+    assert_eq!(metrics(None).0, 401);
+    assert_eq!(metrics(Some(server.token(api::Role::Player))).0, 403);
+    let (status, body) = metrics(Some(server.token(api::Role::Admin)));
+    assert_eq!(status, 200);
+    assert!(body.contains("tide_connected_players"), "{}", body);
+}
+
+#[test]
+fn projectiles_move_with_simulated_time() {
+    let server = TestServer::start();
+    let mut alice = server.connect();
+    alice
+        .fire_projectile(
+            tide::gameplay::PROJECTILE0,
+            api::Vec2::new(10.0, 10.0),
+            api::Vec2::new(0.0, 1.0),
+        )
+        .unwrap();
+    let state = wait_for_state(&mut alice, "the projectile", |state| {
+        state.projectiles.len() == 1
+    });
+    let fired_from = state.projectiles[0].projectile.origin.xy;
+    assert_eq!(fired_from, api::Vec2::new(10.0, 10.0));
+    let speed = state.projectiles[0].current_info.speed.unwrap();
+
+    // (time stands still until we move it)
+    server.advance(Duration::from_secs(1));
+    wait_for_state(&mut alice, "the projectile to move", |state| {
+        let moved_to = state.projectiles[0].projectile.origin.xy;
+        (moved_to - (fired_from + api::Vec2::new(0.0, speed))).norm() < 1e-3
+    });
+}
+
+#[test]
+fn reloaded_catalogs_are_sent_to_clients() {
+    let server = TestServer::start();
+    let mut alice = server.connect();
+    let mut catalog = tide::gameplay::built_in();
+    // (a new kind of enemy is just data too)
+    let mut brute = catalog.enemies[tide::gameplay::GRUNT].clone();
+    brute.health = 30;
+    catalog.enemies.insert("BRUTE".to_owned(), brute);
+    catalog.waves = vec![api::Wave {
+        after_ms: 1_000,
+        count: 2,
+        enemy_type: Some("BRUTE".to_owned()),
+    }];
+    server.reload_catalog(catalog.clone());
+
+    wait_for(&mut alice, "the new catalog", |event| match event {
+        Event::Update(api::ServerUpdate::Catalog(received)) if received == catalog => Some(()),
+        _ => None,
+    });
+    assert_eq!(alice.catalog(), Some(&catalog));
+    // (still connected, and playing by it)
+    server.advance(Duration::from_secs(1));
+    wait_for_state(&mut alice, "the wave", |state| {
+        state.enemies.len() == 2
+            && state
+                .enemies
+                .iter()
+                .all(|enemy| enemy.enemy_type == "BRUTE" && enemy.health == 30)
+    });
+}
+
+#[test]
+fn shutdown_tells_clients_why() {
+    let server = TestServer::start();
+    let mut alice = server.connect();
+    let mut spectator = server.spectate();
+    server.shut_down("maintenance").unwrap();
+
+    for client in [&mut alice, &mut spectator].iter_mut() {
+        let reason = wait_for(client, "the shutdown notice", |event| match event {
+            Event::Update(api::ServerUpdate::ServerShutdown(message)) => Some(message.reason),
+            _ => None,
+        });
+        assert_eq!(reason, "maintenance");
+        let code = wait_for(client, "the connection to close", |event| match event {
+            Event::Closed { code, .. } => Some(code),
+            _ => None,
+        });
+        assert_eq!(code, ws::CloseCode::Away);
+    }
+}
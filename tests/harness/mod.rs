@@ -0,0 +1,280 @@
+//! Runs the whole server (websockets plus the game controller) on an ephemeral
+//! localhost port, on a manual clock, for tests to connect clients to.
+
+use tide::api_types as api;
+use tide::client::{Client, ClientError, ConnectOptions, Event};
+use tide::clock::{Clock, ManualClock};
+use tide::{
+    admin, auth, backpressure, config, game_control, gameplay, geography, intercomm, metrics,
+    rate_limit, resolver, server, sessions, utils,
+};
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// when the server authenticates clients (see `TestServer::start_authenticating`)
+pub const AUTH_SECRET: &[u8] = b"end-to-end tests";
+
+/// how long to wait for something to happen before failing the test
+pub const PATIENCE: Duration = Duration::from_secs(5);
+
+pub struct TestServer {
+    /// e.g. "ws://127.0.0.1:41234"
+    pub address: String,
+    /// the server's (and its clients') time, which only moves when told to
+    pub clock: Arc<ManualClock>,
+    authenticating: bool,
+    /// for tokens, when authenticating
+    next_player_id: AtomicU32,
+    shutdown: utils::CancellationToken,
+    broadcaster: ws::Sender,
+    catalog_reloads: mpsc::Sender<api::Catalog>,
+    thread: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        TestServer::start_with(false)
+    }
+
+    /// clients need a token (see `connect_as`), and admins can connect
+    pub fn start_authenticating() -> TestServer {
+        TestServer::start_with(true)
+    }
+
+    fn start_with(authenticating: bool) -> TestServer {
+        let clock = Arc::new(ManualClock::new(utils::unix_time()));
+        let shutdown = utils::CancellationToken::new();
+        let (started_tx, started_rx) = mpsc::channel();
+        let thread_clock = clock.clone();
+        let thread_shutdown = shutdown.clone();
+        let thread = thread::Builder::new()
+            .name("test server".to_owned())
+            .spawn(move || serve(thread_clock, thread_shutdown, authenticating, started_tx))
+            .unwrap();
+        let (port, broadcaster, catalog_reloads) = match started_rx.recv() {
+            Ok(started) => started,
+            Err(_) => panic!(
+                "the test server failed to start: {:?}",
+                thread.join().unwrap()
+            ),
+        };
+        TestServer {
+            address: format!("ws://127.0.0.1:{}", port),
+            clock,
+            authenticating,
+            next_player_id: AtomicU32::new(1),
+            shutdown,
+            broadcaster,
+            catalog_reloads,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn options(&self) -> ConnectOptions {
+        ConnectOptions {
+            clock: self.clock.clone(),
+            ..ConnectOptions::default()
+        }
+    }
+
+    /// a new player
+    pub fn connect(&self) -> Client {
+        if self.authenticating {
+            return self.connect_as(api::Role::Player, "");
+        }
+        Client::connect(&self.address, self.options()).expect("failed to connect")
+    }
+
+    /// with a token for `role` (the server must be authenticating), at `path` (e.g. "/admin")
+    pub fn connect_as(&self, role: api::Role, path: &str) -> Client {
+        self.connect_with_token(&self.token(role), path)
+            .expect("failed to connect")
+    }
+
+    /// for someone new in `role` (the server must be authenticating)
+    pub fn token(&self, role: api::Role) -> String {
+        assert!(self.authenticating, "the test server doesn't authenticate");
+        let player_id = self.next_player_id.fetch_add(1, Ordering::Relaxed);
+        let claims = auth::Claims {
+            player_id,
+            display_name: format!("{:?} {}", role, player_id),
+            role,
+            exp: utils::unix_time().as_secs() + 3_600,
+        };
+        auth::issue_token(AUTH_SECRET, &claims)
+    }
+
+    /// at `path` (e.g. "/admin"), which may be refused
+    pub fn connect_with_token(&self, token: &str, path: &str) -> Result<Client, ClientError> {
+        let options = ConnectOptions {
+            token: Some(token.to_owned()),
+            ..self.options()
+        };
+        Client::connect(&format!("{}{}", self.address, path), options)
+    }
+
+    /// plain http (e.g. for /metrics): the response's status code, and body
+    pub fn get(&self, path: &str, token: Option<&str>) -> (u16, String) {
+        let mut stream = TcpStream::connect(self.address.trim_start_matches("ws://")).unwrap();
+        stream.set_read_timeout(Some(PATIENCE)).unwrap();
+        let authorization = match token {
+            Some(token) => format!("Authorization: Bearer {}\r\n", token),
+            None => String::new(),
+        };
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+            path, authorization
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = match response.split_once("\r\n\r\n") {
+            Some((_, body)) => body.to_owned(),
+            None => String::new(),
+        };
+        (status, body)
+    }
+
+    pub fn spectate(&self) -> Client {
+        Client::connect(
+            &format!("{}{}", self.address, server::SPECTATE_PATH),
+            self.options(),
+        )
+        .expect("failed to connect")
+    }
+
+    /// moves simulated time forward (projectiles move by it on the next tick)
+    pub fn advance(&self, by: Duration) {
+        self.clock.advance(by);
+    }
+
+    /// as if the gameplay catalog file changed (see `gameplay::watch`)
+    pub fn reload_catalog(&self, catalog: api::Catalog) {
+        self.catalog_reloads.send(catalog).unwrap();
+    }
+
+    /// as upon SIGTERM: tells every client why, closes their connections, and stops
+    pub fn shut_down(mut self, reason: &str) -> Result<(), String> {
+        self.shutdown.cancel();
+        server::drain_websockets_server(&self.broadcaster, reason);
+        self.thread.take().unwrap().join().unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.shutdown.cancel();
+            let _ = self.broadcaster.shutdown();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// what `serve` hands back once it's listening
+type Started = (u16, ws::Sender, mpsc::Sender<api::Catalog>);
+
+/// as `main` does, but on an ephemeral port; returns once the server has stopped
+fn serve(
+    clock: Arc<ManualClock>,
+    shutdown: utils::CancellationToken,
+    authenticating: bool,
+    started: mpsc::Sender<Started>,
+) -> Result<(), String> {
+    let clock: Arc<dyn Clock> = clock;
+    let (update_channel_tx, update_channel_rx) =
+        intercomm::inbox(config::CONTROLLER_INBOX_CAPACITY());
+    let resolver = resolver::SequentialPlayerIdResolver::new(1);
+    let sessions = Arc::new(sessions::SessionRegistry::new(Duration::from_millis(
+        config::RESUME_GRACE_PERIOD_MS(),
+    )));
+    let rate_limit_counters = rate_limit::RateLimitCounters::default();
+    let backpressure_counters = backpressure::BackpressureCounters::default();
+    let metrics = Arc::new(metrics::Metrics::default());
+    let admin = admin::AdminState::new(admin::AuditTrail::open(None).unwrap());
+    let shared_catalog = Arc::new(gameplay::SharedCatalog::new(&gameplay::built_in()));
+    let (socket, broadcaster) = server::set_up_websockets_server(server::ServerResources {
+        update_channel: &update_channel_tx,
+        resolver: &resolver,
+        sessions: &sessions,
+        shutdown: &shutdown,
+        auth_secret: if authenticating { Some(AUTH_SECRET) } else { None },
+        rate_limit_counters: &rate_limit_counters,
+        backpressure_counters: &backpressure_counters,
+        admin: &admin,
+        metrics: &metrics,
+        clock: &*clock,
+        catalog: &shared_catalog,
+    });
+    let socket = socket
+        .bind("127.0.0.1:0")
+        .map_err(|error| format!("{:?}", error))?;
+    let port = socket.local_addr().map_err(|error| format!("{:?}", error))?.port();
+
+    let mut game = game_control::GameController::new(
+        update_channel_rx,
+        broadcaster.clone(),
+        geography::GameMap { max_dimension: 100 },
+        sessions.clone(),
+        metrics.clone(),
+        clock.clone(),
+    );
+    let (catalog_reloads_tx, catalog_reloads_rx) = mpsc::channel();
+    game.reload_catalog_from(catalog_reloads_rx);
+    game.share_catalog_with(shared_catalog.clone());
+    let game_controller = game_control::start_game_controller_thread(
+        game,
+        shutdown.child_token(),
+        update_channel_tx.clone(),
+    )
+    .map_err(|error| format!("{:?}", error))?;
+    started
+        .send((port, broadcaster, catalog_reloads_tx))
+        .unwrap();
+
+    let served = socket.run().map(|_| ()).map_err(|error| format!("{:?}", error));
+    shutdown.cancel();
+    game_controller.join()?;
+    served
+}
+
+/// Reads `client`'s events until `matching` picks one out, failing the test if
+/// none does in time.
+pub fn wait_for<T>(
+    client: &mut Client,
+    what: &str,
+    mut matching: impl FnMut(Event) -> Option<T>,
+) -> T {
+    let deadline = Instant::now() + PATIENCE;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match client.next_event_timeout(remaining) {
+            Some(event) => {
+                if let Some(found) = matching(event) {
+                    return found;
+                }
+            }
+            None => panic!("gave up waiting for {}", what),
+        }
+    }
+}
+
+/// the first game state which satisfies `condition`
+pub fn wait_for_state(
+    client: &mut Client,
+    what: &str,
+    condition: impl Fn(&api::GameState) -> bool,
+) -> api::GameState {
+    wait_for(client, what, |event| match event {
+        Event::GameState(state) if condition(&state) => Some(state),
+        _ => None,
+    })
+}